@@ -7,19 +7,49 @@
 //!
 //! Inspired by this [Python script](https://pastebin.com/emFNyUXe).
 
-use clap::{App, Arg};
-use dirs;
-use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use chrono::{Local, NaiveDateTime};
+use clap::{App, Arg, ArgMatches, ErrorKind};
+use flate2::read::GzDecoder;
+use lazy_static::lazy_static;
+use log::{debug, info, trace, warn, LevelFilter};
+use notify::event::{EventKind, ModifyKind};
+use notify::{Event, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+#[cfg(feature = "desktop-notifications")]
 use notify_rust::{self, Notification, Timeout, Urgency};
-use regex::Regex;
-use serde::Deserialize;
+use regex::{Captures, Regex, RegexBuilder};
+#[cfg(feature = "desktop-notifications")]
+use serde::de::{self, Unexpected};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "desktop-notifications")]
+use serde::{Deserializer, Serializer};
+use serde_json::json;
+use std::borrow::Cow;
+#[cfg(feature = "desktop-notifications")]
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::error;
 use std::fmt;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufReader, SeekFrom};
+use std::io::{self, BufReader, IsTerminal, SeekFrom};
+use std::iter;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
+#[cfg(feature = "desktop-notifications")]
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+#[cfg(any(test, feature = "recording"))]
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use termcolor::{Color, ColorChoice, ColorSpec, NoColor, StandardStream, WriteColor};
 
 /// An error thrown during execution of the program
 #[derive(Debug)]
@@ -29,11 +59,61 @@ pub enum AtlasError {
     /// Something went wrong when reading the log file
     IoError(std::io::Error),
     /// Something went wrong when notifying the user
+    #[cfg(feature = "desktop-notifications")]
     NotifyError(notify_rust::error::Error),
     /// Configuration is not usable
     ConfigError(String),
     /// Something went wrong while parsing the configuration
     TomlError(toml::de::Error),
+    /// Something went wrong while printing the configuration
+    TomlSerializeError(toml::ser::Error),
+    /// Something went wrong while parsing a JSON configuration
+    JsonError(serde_json::Error),
+    /// A configured regular expression couldn't be compiled
+    RegexError(regex::Error),
+    /// Something went wrong when installing the signal handler
+    SignalError(ctrlc::Error),
+    /// The command line arguments couldn't be parsed
+    ArgsError(clap::Error),
+    /// Something went wrong while reading the lines of a log file
+    ReadError {
+        /// Log file being read
+        path: PathBuf,
+        /// Number of the line being read, starting at 1, if reading got
+        /// that far
+        line: Option<u64>,
+        /// What went wrong
+        source: Box<AtlasError>,
+    },
+}
+
+impl AtlasError {
+    /// Returns the name of the variant, which stays the same across versions.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AtlasError::FsNotifyError(_) => "FsNotifyError",
+            AtlasError::IoError(_) => "IoError",
+            #[cfg(feature = "desktop-notifications")]
+            AtlasError::NotifyError(_) => "NotifyError",
+            AtlasError::ConfigError(_) => "ConfigError",
+            AtlasError::TomlError(_) => "TomlError",
+            AtlasError::TomlSerializeError(_) => "TomlSerializeError",
+            AtlasError::JsonError(_) => "JsonError",
+            AtlasError::RegexError(_) => "RegexError",
+            AtlasError::SignalError(_) => "SignalError",
+            AtlasError::ArgsError(_) => "ArgsError",
+            AtlasError::ReadError { .. } => "ReadError",
+        }
+    }
+
+    /// Describes the error as JSON object for scripts, like
+    /// `{"error_kind": "ConfigError", "message": "..."}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "error_kind": self.kind(),
+            "message": self.to_string(),
+        })
+    }
 }
 
 impl From<notify::Error> for AtlasError {
@@ -48,6 +128,7 @@ impl From<std::io::Error> for AtlasError {
     }
 }
 
+#[cfg(feature = "desktop-notifications")]
 impl From<notify_rust::error::Error> for AtlasError {
     fn from(e: notify_rust::error::Error) -> Self {
         AtlasError::NotifyError(e)
@@ -60,14 +141,62 @@ impl From<toml::de::Error> for AtlasError {
     }
 }
 
+impl From<toml::ser::Error> for AtlasError {
+    fn from(e: toml::ser::Error) -> Self {
+        AtlasError::TomlSerializeError(e)
+    }
+}
+
+impl From<serde_json::Error> for AtlasError {
+    fn from(e: serde_json::Error) -> Self {
+        AtlasError::JsonError(e)
+    }
+}
+
+impl From<regex::Error> for AtlasError {
+    fn from(e: regex::Error) -> Self {
+        AtlasError::RegexError(e)
+    }
+}
+
+impl From<ctrlc::Error> for AtlasError {
+    fn from(e: ctrlc::Error) -> Self {
+        AtlasError::SignalError(e)
+    }
+}
+
 impl fmt::Display for AtlasError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AtlasError::FsNotifyError(e) => write!(f, "AtlasError::FsNotifyError: {}", e),
             AtlasError::IoError(e) => write!(f, "AtlasError::IoError: {}", e),
+            #[cfg(feature = "desktop-notifications")]
             AtlasError::NotifyError(e) => write!(f, "AtlasError::NotifyError: {}", e),
             AtlasError::ConfigError(e) => write!(f, "AtlasError::ConfigError: {}", e),
             AtlasError::TomlError(e) => write!(f, "AtlasError::TomlError: {}", e),
+            AtlasError::TomlSerializeError(e) => {
+                write!(f, "AtlasError::TomlSerializeError: {}", e)
+            }
+            AtlasError::JsonError(e) => write!(f, "AtlasError::JsonError: {}", e),
+            AtlasError::RegexError(e) => write!(f, "AtlasError::RegexError: {}", e),
+            AtlasError::SignalError(e) => write!(f, "AtlasError::SignalError: {}", e),
+            AtlasError::ArgsError(e) => write!(f, "AtlasError::ArgsError: {}", e.message),
+            AtlasError::ReadError {
+                path,
+                line: Some(line),
+                source,
+            } => write!(
+                f,
+                "AtlasError::ReadError: {} at line {}: {}",
+                path.display(),
+                line,
+                source
+            ),
+            AtlasError::ReadError {
+                path,
+                line: None,
+                source,
+            } => write!(f, "AtlasError::ReadError: {}: {}", path.display(), source),
         }
     }
 }
@@ -77,33 +206,565 @@ impl error::Error for AtlasError {
         match self {
             AtlasError::FsNotifyError(e) => Some(e),
             AtlasError::IoError(e) => Some(e),
+            #[cfg(feature = "desktop-notifications")]
             AtlasError::NotifyError(e) => Some(e),
             AtlasError::ConfigError(_) => None,
             AtlasError::TomlError(e) => Some(e),
+            AtlasError::TomlSerializeError(e) => Some(e),
+            AtlasError::JsonError(e) => Some(e),
+            AtlasError::RegexError(e) => Some(e),
+            AtlasError::SignalError(e) => Some(e),
+            AtlasError::ArgsError(e) => Some(e),
+            AtlasError::ReadError { source, .. } => Some(source.as_ref()),
         }
     }
 }
 
+/// Flag printing errors as JSON, checked before the arguments are parsed to
+/// also cover errors while parsing them.
+pub const JSON_ERRORS_FLAG: &str = "--json-errors";
+
+/// Environment variable naming the log file if none is configured.
+const LOGFILE_ENV_VAR: &str = "BRICKATLAS_LOGFILE";
+/// Environment variable listing the maps, separated by commas, if none are
+/// configured.
+const MAPS_ENV_VAR: &str = "BRICKATLAS_MAPS";
+
+/// Default milliseconds file changes are collected before being handled.
+const DEFAULT_DEBOUNCE_MS: u64 = 1000;
+/// Minimal milliseconds file changes are collected before being handled.
+const MIN_DEBOUNCE_MS: u64 = 50;
+
+/// Default number of lines read at most whenever a log file changed.
+const DEFAULT_MAX_LINES_PER_EVENT: usize = 10000;
+
+/// Default milliseconds between checks of the log files in poll mode.
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+
+/// Default number of lines modifiers are remembered for the next map.
+const DEFAULT_MODS_WINDOW_LINES: u64 = 20;
+
+/// Default milliseconds repeats of an event are suppressed.
+const DEFAULT_DEDUP_WINDOW_MS: u64 = 2000;
+
+/// Deserializes an urgency from `low`, `normal` or `critical`, `null` is
+/// left unset.
+#[cfg(feature = "desktop-notifications")]
+fn deserialize_urgency<'de, D>(deserializer: D) -> Result<Option<Urgency>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let urgency = match Option::<String>::deserialize(deserializer)? {
+        Some(urgency) => urgency,
+        None => return Ok(None),
+    };
+    Urgency::try_from(urgency.as_str()).map(Some).map_err(|_| {
+        de::Error::invalid_value(Unexpected::Str(&urgency), &"low, normal or critical")
+    })
+}
+
+/// Serializes an urgency as `low`, `normal` or `critical`.
+#[cfg(feature = "desktop-notifications")]
+fn serialize_urgency<S>(urgency: &Option<Urgency>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match urgency {
+        Some(Urgency::Low) => serializer.serialize_some("low"),
+        Some(Urgency::Normal) => serializer.serialize_some("normal"),
+        Some(Urgency::Critical) => serializer.serialize_some("critical"),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Deserializes a timeout from milliseconds, `0` never expires and `null`
+/// is left unset.
+#[cfg(feature = "desktop-notifications")]
+fn deserialize_timeout<'de, D>(deserializer: D) -> Result<Option<Timeout>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        Option::<u32>::deserialize(deserializer)?.map(|ms| match ms {
+            0 => Timeout::Never,
+            ms => Timeout::Milliseconds(ms),
+        }),
+    )
+}
+
+/// Serializes a timeout as milliseconds, `0` never expires.
+#[cfg(feature = "desktop-notifications")]
+fn serialize_timeout<S>(timeout: &Option<Timeout>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match timeout {
+        Some(Timeout::Never) => serializer.serialize_some(&0),
+        Some(Timeout::Milliseconds(ms)) => serializer.serialize_some(ms),
+        Some(Timeout::Default) | None => serializer.serialize_none(),
+    }
+}
+
+/// Named groups `maps_regex` has to provide.
+const MAPS_REGEX_GROUPS: &[&str] = &["map"];
+/// Named groups `buy_regex` and `buy_regexes` have to provide.
+///
+/// The groups `tab`, `left` and `top` can be added to show where the item is
+/// in the stash. `location` may be left unmatched by whispers not telling it.
+const BUY_REGEX_GROUPS: &[&str] = &["buyer", "object", "price", "league", "location"];
+/// Named groups `zones_regex` has to provide.
+const ZONES_REGEX_GROUPS: &[&str] = &["zone"];
+/// Named groups `level_regex` has to provide.
+const LEVEL_REGEX_GROUPS: &[&str] = &["character", "level"];
+/// Named groups `afk_regex` has to provide, `state` is either `ON` or `OFF`.
+const AFK_REGEX_GROUPS: &[&str] = &["state"];
+/// Named groups `character_regex` has to provide, `league` can be added.
+const CHARACTER_REGEX_GROUPS: &[&str] = &["character"];
+/// Named groups `mods_regex` has to provide.
+const MODS_REGEX_GROUPS: &[&str] = &["mods"];
+/// Named groups `trade_regex` has to provide, `items` can be added.
+const TRADE_REGEX_GROUPS: &[&str] = &["partner"];
+
+/// Maps to avoid with their own notification settings.
+///
+/// Settings missing in the group are taken from the configuration.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MapGroup {
+    /// Name of the group.
+    name: String,
+    /// Maps belonging to the group, `re:` entries are regexes.
+    #[serde(default)]
+    maps: Vec<String>,
+    /// Summary of the map notification, `{map}` is replaced by the map name.
+    notification_summary: Option<String>,
+    /// Body of the map notification, `{map}` is replaced by the map name.
+    notification_body: Option<String>,
+    /// Urgency of the map notification.
+    #[cfg(feature = "desktop-notifications")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_urgency",
+        serialize_with = "serialize_urgency"
+    )]
+    urgency: Option<Urgency>,
+    /// Milliseconds the map notification is shown, `0` shows it until
+    /// dismissed.
+    #[cfg(feature = "desktop-notifications")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_timeout",
+        serialize_with = "serialize_timeout"
+    )]
+    timeout_ms: Option<Timeout>,
+    /// Sound file played together with the map notification instead of
+    /// `sound`.
+    sound: Option<String>,
+    #[serde(skip)]
+    avoided_maps: Option<AvoidedMaps>,
+}
+
+impl MapGroup {
+    /// Returns the name of the group.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// How changes of the log files are noticed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum WatchMode {
+    /// Events of the operating system
+    #[default]
+    Native,
+    /// Checking the files every `poll_interval_ms`, for file systems without
+    /// working events like network shares
+    Poll,
+}
+
+/// What happens to notifications within `min_notification_interval_ms` of
+/// the previous one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum NotificationLimit {
+    /// Shown once the interval passed, holding up later lines meanwhile
+    #[default]
+    Delay,
+    /// Not shown at all
+    Drop,
+}
+
+/// Where events are shown without `notifiers` configured.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_NOTIFIER: NotifierKind = NotifierKind::Desktop;
+/// Where events are shown without `notifiers` configured.
+#[cfg(not(feature = "desktop-notifications"))]
+const DEFAULT_NOTIFIER: NotifierKind = NotifierKind::Stdout;
+
+/// Where events are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum NotifierKind {
+    /// Desktop notifications
+    Desktop,
+    /// Lines printed to stdout
+    Stdout,
+    /// Lines appended to `notification_file`
+    File,
+    /// JSON posted to `webhook_url`, only done for map and buyer events
+    Webhook,
+    /// Nowhere, to not show an event type at all
+    None,
+}
+
+/// How entered maps are compared to the maps to avoid.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum MatchMode {
+    /// The entered map has to equal a map to avoid.
+    #[default]
+    Exact,
+    /// The entered map has to contain a map to avoid.
+    Contains,
+}
+
 /// Stores the configuration for the application.
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Config {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     logfile: String,
     #[serde(default)]
+    logfiles: Vec<String>,
+    #[serde(default)]
     maps: Vec<String>,
+    /// Alert when entering maps not listed in `maps` instead.
+    #[serde(default)]
+    invert_maps: bool,
+    /// Plaintext file with further maps to avoid, one per line.
+    #[serde(default)]
+    maps_file: String,
+    /// JSON file listing maps as `{ "name": "Strand", "avoid": true }`,
+    /// those to avoid are added to `maps`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    maps_json: String,
+    /// How entered maps are compared to the maps to avoid.
+    #[serde(default)]
+    match_mode: MatchMode,
+    /// Compare map names ignoring their case.
+    #[serde(default)]
+    case_insensitive: bool,
+    /// Compare map names without the `map_name_suffixes`, so `Strand`
+    /// matches `Strand Map`.
+    #[serde(default)]
+    normalize_map_names: bool,
+    /// Suffixes removed with `normalize_map_names`, `[" Map"]` if absent.
+    map_name_suffixes: Option<Vec<String>>,
+    #[serde(skip)]
+    avoided_maps: Option<AvoidedMaps>,
+    /// Avoid all maps of at least this tier, if `maps_regex` captures the
+    /// named group `tier`.
+    min_tier: Option<u32>,
+    /// Further maps to avoid, notified with their own settings.
+    #[serde(default, rename = "map_group")]
+    map_groups: Vec<MapGroup>,
+    #[serde(default)]
     maps_regex: String,
+    /// Match `maps_regex` ignoring case.
+    ///
+    /// Inline flags like `(?i)` work as well and take precedence within the
+    /// group they're set for, so `(?-i)` switches this off again there.
+    #[serde(default)]
+    maps_regex_case_insensitive: bool,
     #[serde(skip)]
     maps_regex_compiled: Option<Regex>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     buy_regex: String,
+    /// Match `buy_regex` and `buy_regexes` ignoring case, like
+    /// `maps_regex_case_insensitive`.
+    #[serde(default)]
+    buy_regex_case_insensitive: bool,
+    /// Further regexes for buy whispers, tried in order after `buy_regex`.
+    #[serde(default)]
+    buy_regexes: Vec<String>,
+    #[serde(skip)]
+    buy_regexes_compiled: Option<Vec<Regex>>,
+    /// Notify about entering `zones` if set.
+    #[serde(default)]
+    zones_regex: String,
+    #[serde(skip)]
+    zones_regex_compiled: Option<Regex>,
+    /// Zones to notify about when entering them.
+    #[serde(default)]
+    zones: Vec<String>,
+    /// Notify about level ups if set.
+    #[serde(default)]
+    level_regex: String,
+    #[serde(skip)]
+    level_regex_compiled: Option<Regex>,
+    /// Notify about AFK mode changes if set.
+    #[serde(default)]
+    afk_regex: String,
+    #[serde(skip)]
+    afk_regex_compiled: Option<Regex>,
+    /// Notify about switching to another character if set.
+    #[serde(default)]
+    character_regex: String,
+    #[serde(skip)]
+    character_regex_compiled: Option<Regex>,
+    /// Lines telling the modifiers of the map entered next, if set.
+    ///
+    /// The `mods` are remembered for `mods_window_lines` lines. Entering a
+    /// map within them takes them as the map's modifiers, so the map is
+    /// avoided if any of them contains one of the `avoid_mods`.
+    #[serde(default)]
+    mods_regex: String,
+    #[serde(skip)]
+    mods_regex_compiled: Option<Regex>,
+    /// Parts of modifiers making maps to avoid, ignoring their case.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    avoid_mods: Vec<String>,
+    /// Lines the modifiers read by `mods_regex` are remembered, 20 if absent.
+    mods_window_lines: Option<u64>,
+    /// Notify about completed trades if set.
+    #[serde(default)]
+    trade_regex: String,
+    #[serde(skip)]
+    trade_regex_compiled: Option<Regex>,
+    /// Prefix of the whisper messages to notify about, defaults to incoming
+    /// whispers.
+    whisper_prefix: Option<String>,
+    /// Number of lines already in the log files handled on startup.
+    #[serde(default)]
+    backlog_lines: usize,
+    /// Milliseconds file changes are collected before being handled.
+    ///
+    /// The first change is handled right away, changes following within this
+    /// time are handled together once it passed. Longer times read the log
+    /// less often but may delay notifications for lines written in bursts.
+    /// Values below 50 are raised to 50 to not thrash on every write.
+    debounce_ms: Option<u64>,
+    /// Lines read at most whenever a log file changed, the rest is skipped
+    /// to not flood the notifications.
+    max_lines_per_event: Option<usize>,
+    /// How changes of the log files are noticed.
+    #[serde(default)]
+    watch_mode: WatchMode,
+    /// Milliseconds between checks of the log files with `watch_mode = "poll"`.
+    poll_interval_ms: Option<u64>,
+    /// Where events are shown, desktop notifications and `webhook_url` if
+    /// empty. `event_socket` gets the events in any case.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notifiers: Vec<NotifierKind>,
+    /// Where map alerts are shown, as `notifiers` if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    map_notifiers: Vec<NotifierKind>,
+    /// Where buy offers are shown, as `notifiers` if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    buyer_notifiers: Vec<NotifierKind>,
+    /// File the `file` notifier appends events to as lines of text.
+    notification_file: Option<String>,
+    /// Print events to stdout instead of showing desktop notifications.
+    #[serde(default)]
+    dry_run: bool,
+    /// Milliseconds repeats of an event are suppressed, `0` disables it.
+    dedup_window_ms: Option<u64>,
+    /// Milliseconds at least between two notifications of any kind.
+    min_notification_interval_ms: Option<u64>,
+    /// What happens to notifications coming too soon after the previous one.
+    #[serde(default)]
+    notification_limit: NotificationLimit,
+    /// Seconds after which lines are too old to notify about, lines without
+    /// timestamp are always current.
+    max_event_age_secs: Option<u64>,
+    /// Notify once watching started.
+    #[serde(default)]
+    startup_notification: bool,
+    /// Only notify about offers worth at least this much, in the currency
+    /// `exchange_rates` are expressed in.
+    min_price: Option<f64>,
+    /// Value of each currency, like `{ chaos = 1, divine = 200 }`.
+    #[serde(default)]
+    exchange_rates: HashMap<String, f64>,
+    /// Only notify about offers whose object contains one of these
+    /// keywords, ignoring their case, like `["unique"]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rarity: Vec<String>,
+    /// Buyers never notified about, ignoring their case.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ignored_buyers: Vec<String>,
+    #[serde(skip)]
+    ignored_buyers_normalized: Option<Vec<String>>,
+    /// Shorter names of currencies shown in buyer notifications, like
+    /// `{ chaos = "c", divine = "div" }`.
+    #[serde(default)]
+    currency_aliases: HashMap<String, String>,
+    /// Log file to print the events of instead of watching.
+    #[serde(skip)]
+    test_file: Option<String>,
+    /// Print the configuration instead of watching.
+    #[serde(skip)]
+    print_config: bool,
+    /// Handle the lines already in the log files and exit instead of
+    /// watching.
+    #[serde(skip)]
+    once: bool,
+    /// Clock telling the time, the system's if absent.
     #[serde(skip)]
-    buy_regex_compiled: Option<Regex>,
+    clock: Option<Arc<dyn Clock>>,
+    /// Wait for missing log files to appear instead of failing.
+    #[serde(default)]
+    wait_for_logfile: bool,
+    /// Seconds to wait for missing log files, waits forever if absent.
+    wait_for_logfile_timeout: Option<u64>,
+    /// Watch the files symlinked log files point to instead of the links.
+    ///
+    /// Links pointing somewhere else later on are followed to their new
+    /// file, which is read from its start.
+    #[serde(default)]
+    follow_symlinks: bool,
+    /// Milliseconds the map notification is shown, `0` shows it until
+    /// dismissed.
+    #[cfg(feature = "desktop-notifications")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_timeout",
+        serialize_with = "serialize_timeout"
+    )]
+    map_timeout_ms: Option<Timeout>,
+    /// Urgency of the map notification, critical if absent.
+    #[cfg(feature = "desktop-notifications")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_urgency",
+        serialize_with = "serialize_urgency"
+    )]
+    map_urgency: Option<Urgency>,
+    /// Urgency of the buyer notification, normal if absent.
+    #[cfg(feature = "desktop-notifications")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_urgency",
+        serialize_with = "serialize_urgency"
+    )]
+    buyer_urgency: Option<Urgency>,
+    /// Milliseconds the buyer notification is shown, `0` shows it until
+    /// dismissed.
+    #[cfg(feature = "desktop-notifications")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_timeout",
+        serialize_with = "serialize_timeout"
+    )]
+    buyer_timeout_ms: Option<Timeout>,
+    /// Don't show map and buyer notifications while in the hideout.
+    #[serde(default)]
+    suppress_in_hideout: bool,
+    /// Whether entered maps are checked, enabled if absent.
+    map_alerts_enabled: Option<bool>,
+    /// Add the number of map alerts this session to their summary, like
+    /// `brickatlas map (#3)`.
+    #[serde(default)]
+    map_alert_counter: bool,
+    /// Whether buy whispers are checked, enabled if absent.
+    buyer_alerts_enabled: Option<bool>,
+    /// Replace the previous map notification instead of adding another one,
+    /// only supported on Linux and BSD.
+    #[serde(default)]
+    replace_map_notifications: bool,
+    /// Icon file shown with the map notification.
+    map_icon: Option<String>,
+    /// Icon file shown with the buyer notification.
+    buyer_icon: Option<String>,
+    /// Times showing a notification is retried, three if absent.
+    notification_retries: Option<u32>,
+    /// Milliseconds to wait before retrying to show a notification, doubled
+    /// for each further retry up to 30 seconds.
+    notification_backoff_ms: Option<u64>,
+    /// Summary of the map notification, `{map}` is replaced by the map name.
+    map_notification_summary: Option<String>,
+    /// Body of the map notification, `{map}` is replaced by the map name.
+    map_notification_body: Option<String>,
+    /// File every entered map is appended to as JSON line.
+    history_file: Option<String>,
+    /// File every buy offer is appended to as tab separated line.
+    buyer_log_file: Option<String>,
+    /// Bytes after which `buyer_log_file` is moved to `.1` and started anew,
+    /// it grows without limit if absent.
+    buyer_log_max_bytes: Option<u64>,
+    /// File the read positions are saved to on shutdown, to continue from
+    /// there on the next start.
+    state_file: Option<String>,
+    /// Unix socket map and buyer events are streamed to as JSON lines.
+    event_socket: Option<String>,
+    /// URL map and buyer events are posted to as JSON.
+    webhook_url: Option<String>,
+    /// Sound file played together with the map notification, unless the map
+    /// group of the map has its own.
+    sound: Option<String>,
+    /// Command used to play `sound`, the file is passed as last argument.
+    sound_player: Option<String>,
+    /// Line typed on stdin toggling the snooze, snoozing is disabled if
+    /// absent. Can't be used with the configuration read from stdin.
+    snooze_command: Option<String>,
+    /// Minutes notifications stay snoozed, five if absent.
+    snooze_minutes: Option<u64>,
+    /// Seconds between log messages telling that watching is still going on,
+    /// shown from verbosity `-v` on.
+    heartbeat_secs: Option<u64>,
+    /// Also show these messages as notifications.
+    #[serde(default)]
+    heartbeat_notification: bool,
+    /// File the configuration was read from, reread when it changes.
+    #[serde(skip)]
+    config_file: Option<PathBuf>,
+    /// Settings given as command line arguments, applied again after
+    /// rereading the configuration file.
+    #[serde(skip)]
+    arg_overrides: ArgOverrides,
+}
+
+/// Settings given as command line arguments, taking precedence over the
+/// configuration file.
+#[derive(Debug, Default, Clone)]
+struct ArgOverrides {
+    dry_run: bool,
+    test_file: Option<String>,
+    once: bool,
+    print_config: bool,
+    wait_for_logfile: bool,
+    follow_symlinks: bool,
+    logfiles: Option<Vec<String>>,
+    maps: Vec<String>,
+}
+
+impl ArgOverrides {
+    /// Collects the settings given in `matches`.
+    fn from_matches(matches: &ArgMatches<'_>) -> ArgOverrides {
+        ArgOverrides {
+            dry_run: matches.is_present("dry_run"),
+            test_file: matches.value_of("test").map(String::from),
+            once: matches.is_present("once"),
+            print_config: matches.is_present("print_config"),
+            wait_for_logfile: matches.is_present("wait_for_logfile"),
+            follow_symlinks: matches.is_present("follow_symlinks"),
+            logfiles: matches
+                .values_of("logfile")
+                .map(|logfiles| logfiles.map(String::from).collect()),
+            maps: matches
+                .values_of("maps")
+                .map(|maps| maps.map(String::from).collect())
+                .unwrap_or_default(),
+        }
+    }
 }
 
 impl Config {
     /// Parses the configuration from command line arguments.
     ///
-    /// The first argument is interpreted as the file to watch. The second one
-    /// the maps to look for.
+    /// Log files and maps are taken from the command line first, then from
+    /// the configuration file, then from the `BRICKATLAS_LOGFILE` and
+    /// comma separated `BRICKATLAS_MAPS` environment variables. Without any
+    /// of them the log file is looked for in the usual install locations.
     pub fn new_from_args() -> Result<Config, AtlasError> {
         let default_config = [
             dirs::config_dir()
@@ -117,6 +778,7 @@ impl Config {
         .collect::<PathBuf>();
 
         let matches = App::new("brickatlas")
+            .version(env!("CARGO_PKG_VERSION"))
             .about(
                 format!(
                     r"Notifies when you are about to brick your atlas or get buy whispers.
@@ -130,7 +792,7 @@ By default {} is read as configuration file.",
             .arg(
                 Arg::with_name("configfile")
                     .short("c")
-                    .help("config file to use")
+                    .help("config file to use, - reads it from stdin")
                     .takes_value(true),
             )
             .arg(
@@ -138,11 +800,56 @@ By default {} is read as configuration file.",
                     .short("n")
                     .help("don't read default config file"),
             )
+            .arg(
+                Arg::with_name("verbose")
+                    .short("v")
+                    .long("verbose")
+                    .help("log more details, repeat for even more")
+                    .multiple(true),
+            )
+            .arg(
+                Arg::with_name("dry_run")
+                    .long("dry-run")
+                    .help("print events instead of showing notifications"),
+            )
+            .arg(
+                Arg::with_name("test")
+                    .long("test")
+                    .help("print the events found in a log file and exit")
+                    .value_name("logfile")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("once")
+                    .long("once")
+                    .help("handle the lines already in the log files and exit"),
+            )
+            .arg(
+                Arg::with_name("print_config")
+                    .long("print-config")
+                    .help("print the effective configuration and exit"),
+            )
+            .arg(
+                Arg::with_name("wait_for_logfile")
+                    .short("w")
+                    .help("wait for the log files to appear"),
+            )
+            .arg(
+                Arg::with_name("json_errors")
+                    .long(JSON_ERRORS_FLAG.trim_start_matches('-'))
+                    .help("print errors as JSON to stderr"),
+            )
+            .arg(
+                Arg::with_name("follow_symlinks")
+                    .long("follow-symlinks")
+                    .help("watch the files symlinked log files point to"),
+            )
             .arg(
                 Arg::with_name("logfile")
                     .short("l")
-                    .help("log file to analyze")
-                    .takes_value(true),
+                    .help("log files to analyze")
+                    .takes_value(true)
+                    .multiple(true),
             )
             .arg(
                 Arg::with_name("maps")
@@ -151,9 +858,26 @@ By default {} is read as configuration file.",
                     .takes_value(true)
                     .multiple(true),
             )
-            .get_matches();
+            .get_matches_safe()
+            .or_else(|e| match e.kind {
+                ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+                _ => Err(AtlasError::ArgsError(e)),
+            })?;
+
+        init_logging(matches.occurrences_of("verbose"));
+        info!("brickatlas {}", env!("CARGO_PKG_VERSION"));
 
-        let mut config = if let Some(file) = matches.value_of("configfile") {
+        let mut config = if matches.value_of("configfile") == Some("-") {
+            info!("reading configuration from stdin");
+            let config = Self::new_from_reader(io::stdin().lock())?;
+            // stdin is used up by the configuration, so nothing could be typed
+            if config.snooze_command.is_some() {
+                return Err(AtlasError::ConfigError(String::from(
+                    "snooze_command can't be used with the configuration read from stdin",
+                )));
+            }
+            config
+        } else if let Some(file) = matches.value_of("configfile") {
             Self::new_from_file(file)?
         } else if !matches.is_present("no_default_config") && default_config.is_file() {
             Self::new_from_file(
@@ -162,137 +886,5022 @@ By default {} is read as configuration file.",
                     .expect("Error while extracting default config string for reading"),
             )?
         } else {
+            info!("no configuration file used");
             Default::default()
         };
 
-        if let Some(logfile) = matches.value_of("logfile") {
-            config.logfile = String::from(logfile);
-        }
+        config.arg_overrides = ArgOverrides::from_matches(&matches);
+        config.apply_arg_overrides();
+        config.apply_env_fallbacks();
 
-        if let Some(maps) = matches.values_of("maps") {
-            config.maps.extend(maps.map(String::from));
+        if config.logfiles.is_empty() {
+            if let Some(logfile) = Self::detect_logfile() {
+                info!("detected log file {}", logfile);
+                config.logfiles.push(logfile);
+            }
         }
 
+        config.validate()?;
+        config.compile_avoided_maps()?;
         Ok(config)
     }
 
-    fn maps_regex(&mut self) -> &Regex {
+    /// Takes the log file and maps from the `BRICKATLAS_LOGFILE` and
+    /// `BRICKATLAS_MAPS` environment variables if they're still unset.
+    fn apply_env_fallbacks(&mut self) {
+        if self.logfiles.is_empty() {
+            if let Ok(logfile) = std::env::var(LOGFILE_ENV_VAR) {
+                self.logfiles.push(logfile);
+            }
+        }
+
+        if self.maps.is_empty() {
+            if let Ok(maps) = std::env::var(MAPS_ENV_VAR) {
+                self.maps = maps
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|map| !map.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+        }
+    }
+
+    /// Applies the settings given as command line arguments.
+    fn apply_arg_overrides(&mut self) {
+        let overrides = &self.arg_overrides;
+        self.dry_run |= overrides.dry_run;
+        if let Some(file) = &overrides.test_file {
+            self.test_file = Some(file.clone());
+        }
+        self.once |= overrides.once;
+        self.print_config |= overrides.print_config;
+        self.wait_for_logfile |= overrides.wait_for_logfile;
+        self.follow_symlinks |= overrides.follow_symlinks;
+        if let Some(logfiles) = &overrides.logfiles {
+            self.logfiles = logfiles.clone();
+        }
+        self.maps.extend(overrides.maps.iter().cloned());
+    }
+
+    /// Looks for the log file in the usual PoE install locations.
+    ///
+    /// Checks the Steam and standalone installs on Windows and the Steam,
+    /// Proton and wine locations under the home directory elsewhere.
+    pub fn detect_logfile() -> Option<String> {
+        let candidates: Vec<PathBuf> = if cfg!(windows) {
+            vec![
+                PathBuf::from(
+                    r"C:\Program Files (x86)\Steam\steamapps\common\Path of Exile\logs\Client.txt",
+                ),
+                PathBuf::from(
+                    r"C:\Program Files (x86)\Grinding Gear Games\Path of Exile\logs\Client.txt",
+                ),
+            ]
+        } else {
+            let home = dirs::home_dir()?;
+            [
+                ".steam/steam/steamapps/common/Path of Exile/logs/Client.txt",
+                ".local/share/Steam/steamapps/common/Path of Exile/logs/Client.txt",
+                ".wine/drive_c/Program Files (x86)/Grinding Gear Games/Path of Exile/logs/Client.txt",
+            ]
+            .iter()
+            .map(|p| home.join(p))
+            .collect()
+        };
+        candidates
+            .into_iter()
+            .find(|p| p.is_file())
+            .and_then(|p| p.to_str().map(String::from))
+    }
+
+    /// Checks that the configuration is usable.
+    ///
+    /// The regexes have to be present, compile and provide the named groups
+    /// used for the notifications. `maps_regex` and the buy regexes may be
+    /// missing if their alerts are disabled.
+    pub fn validate(&self) -> Result<(), AtlasError> {
+        let suffixes = self.map_name_suffixes();
+        AvoidedMaps::new(&self.maps, self.case_insensitive, suffixes)?;
+        for group in &self.map_groups {
+            AvoidedMaps::new(&group.maps, self.case_insensitive, suffixes)?;
+        }
+        // regexes of disabled alerts aren't used, so they may be missing
+        if self.map_alerts_enabled.unwrap_or(true) || !self.maps_regex.is_empty() {
+            validate_regex("maps_regex", &self.maps_regex, MAPS_REGEX_GROUPS)?;
+        }
+        if self.buyer_alerts_enabled.unwrap_or(true)
+            && self.buy_regex.is_empty()
+            && self.buy_regexes.is_empty()
+        {
+            return Err(AtlasError::ConfigError(String::from(
+                "buy_regex is missing",
+            )));
+        }
+        if !self.buy_regex.is_empty() {
+            validate_regex("buy_regex", &self.buy_regex, BUY_REGEX_GROUPS)?;
+        }
+        for (i, buy_regex) in self.buy_regexes.iter().enumerate() {
+            validate_regex(&format!("buy_regexes[{}]", i), buy_regex, BUY_REGEX_GROUPS)?;
+        }
+        if !self.zones_regex.is_empty() {
+            validate_regex("zones_regex", &self.zones_regex, ZONES_REGEX_GROUPS)?;
+        }
+        if !self.level_regex.is_empty() {
+            validate_regex("level_regex", &self.level_regex, LEVEL_REGEX_GROUPS)?;
+        }
+        if !self.afk_regex.is_empty() {
+            validate_regex("afk_regex", &self.afk_regex, AFK_REGEX_GROUPS)?;
+        }
+        if !self.character_regex.is_empty() {
+            validate_regex(
+                "character_regex",
+                &self.character_regex,
+                CHARACTER_REGEX_GROUPS,
+            )?;
+        }
+        if !self.mods_regex.is_empty() {
+            validate_regex("mods_regex", &self.mods_regex, MODS_REGEX_GROUPS)?;
+        }
+        if !self.trade_regex.is_empty() {
+            validate_regex("trade_regex", &self.trade_regex, TRADE_REGEX_GROUPS)?;
+        }
+        let kinds = [&self.notifiers, &self.map_notifiers, &self.buyer_notifiers];
+        if self.notification_file.is_none() && kinds.iter().any(|k| k.contains(&NotifierKind::File))
+        {
+            return Err(AtlasError::ConfigError(String::from(
+                "the file notifier needs notification_file",
+            )));
+        }
+        if self.webhook_url.is_none() && kinds.iter().any(|k| k.contains(&NotifierKind::Webhook)) {
+            return Err(AtlasError::ConfigError(String::from(
+                "the webhook notifier needs webhook_url",
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the compiled `maps_regex`, providing the named group `map`.
+    pub fn compiled_maps_regex(&mut self) -> Result<&Regex, AtlasError> {
         let Self {
             maps_regex,
+            maps_regex_case_insensitive,
             maps_regex_compiled,
             ..
         } = self;
-        maps_regex_compiled.get_or_insert_with(|| {
-            Regex::new(maps_regex.as_str()).expect("Error when compiling maps_regex")
-        })
+        compile_regex(
+            maps_regex,
+            *maps_regex_case_insensitive,
+            maps_regex_compiled,
+        )
+    }
+
+    /// Returns the compiled buy regexes in the order they're tried.
+    ///
+    /// Matches are only used if they provide all the named groups `buyer`,
+    /// `object`, `price`, `league` and `location`.
+    pub fn compiled_buy_regexes(&mut self) -> Result<&[Regex], AtlasError> {
+        if self.buy_regexes_compiled.is_none() {
+            let mut compiled = Vec::new();
+            let buy_regexes = std::iter::once(&self.buy_regex)
+                .filter(|r| !r.is_empty())
+                .chain(&self.buy_regexes);
+            for buy_regex in buy_regexes {
+                compiled.push(
+                    RegexBuilder::new(buy_regex)
+                        .case_insensitive(self.buy_regex_case_insensitive)
+                        .build()?,
+                );
+            }
+            self.buy_regexes_compiled = Some(compiled);
+        }
+        Ok(self
+            .buy_regexes_compiled
+            .as_deref()
+            .expect("Error when accessing compiled buy_regexes"))
     }
 
-    fn buy_regex(&mut self) -> &Regex {
+    fn zones_regex(&mut self) -> Result<Option<&Regex>, AtlasError> {
         let Self {
-            buy_regex,
-            buy_regex_compiled,
+            zones_regex,
+            zones_regex_compiled,
             ..
         } = self;
-        buy_regex_compiled.get_or_insert_with(|| {
-            Regex::new(buy_regex.as_str()).expect("Error when compiling buy_regex")
-        })
+        compile_optional_regex(zones_regex, zones_regex_compiled)
     }
 
-    /// Parse configuration from a toml file.
-    pub fn new_from_file(file: &str) -> Result<Config, AtlasError> {
-        Ok(toml::from_str::<Config>(
-            fs::read_to_string(file)?.as_str(),
-        )?)
+    fn level_regex(&mut self) -> Result<Option<&Regex>, AtlasError> {
+        let Self {
+            level_regex,
+            level_regex_compiled,
+            ..
+        } = self;
+        compile_optional_regex(level_regex, level_regex_compiled)
     }
-}
 
-fn handle_event(
-    event: Event,
-    config: &mut Config,
-    file: &mut BufReader<std::fs::File>,
-) -> Result<(), AtlasError> {
-    if event.kind.is_modify() {
-        for line in file.lines() {
-            let line = line?;
+    fn afk_regex(&mut self) -> Result<Option<&Regex>, AtlasError> {
+        let Self {
+            afk_regex,
+            afk_regex_compiled,
+            ..
+        } = self;
+        compile_optional_regex(afk_regex, afk_regex_compiled)
+    }
 
-            if let Some(cap) = config.maps_regex().captures(line.as_str()) {
-                if config
-                    .maps
-                    .iter()
-                    .find(|m| m.as_str() == &cap["map"])
-                    .is_some()
-                {
-                    notify_map()?;
-                }
-            }
-            if let Some(cap) = config.buy_regex().captures(line.as_str()) {
-                notify_buyer(
-                    &cap["buyer"],
-                    &cap["object"],
-                    &cap["price"],
-                    &cap["league"],
-                    &cap["location"],
-                )?;
+    fn character_regex(&mut self) -> Result<Option<&Regex>, AtlasError> {
+        let Self {
+            character_regex,
+            character_regex_compiled,
+            ..
+        } = self;
+        compile_optional_regex(character_regex, character_regex_compiled)
+    }
+
+    fn mods_regex(&mut self) -> Result<Option<&Regex>, AtlasError> {
+        let Self {
+            mods_regex,
+            mods_regex_compiled,
+            ..
+        } = self;
+        compile_optional_regex(mods_regex, mods_regex_compiled)
+    }
+
+    /// Lines modifiers are remembered for the map entered next.
+    fn mods_window_lines(&self) -> u64 {
+        self.mods_window_lines.unwrap_or(DEFAULT_MODS_WINDOW_LINES)
+    }
+
+    fn trade_regex(&mut self) -> Result<Option<&Regex>, AtlasError> {
+        let Self {
+            trade_regex,
+            trade_regex_compiled,
+            ..
+        } = self;
+        compile_optional_regex(trade_regex, trade_regex_compiled)
+    }
+
+    /// Compiles the maps to avoid and those of the map groups, so their
+    /// `re:` entries are checked when loading instead of on first use.
+    fn compile_avoided_maps(&mut self) -> Result<(), AtlasError> {
+        let suffixes = self.map_name_suffixes();
+        let avoided_maps = AvoidedMaps::new(&self.maps, self.case_insensitive, suffixes)?;
+        let groups = self
+            .map_groups
+            .iter()
+            .map(|group| AvoidedMaps::new(&group.maps, self.case_insensitive, suffixes))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.avoided_maps = Some(avoided_maps);
+        for (group, avoided_maps) in self.map_groups.iter_mut().zip(groups) {
+            group.avoided_maps = Some(avoided_maps);
+        }
+        Ok(())
+    }
+
+    /// Returns the entry of the maps to avoid `map` matches, if any.
+    fn avoided_map_entry(&mut self, map: &str) -> Result<Option<String>, AtlasError> {
+        if self.avoided_maps.is_none() {
+            self.compile_avoided_maps()?;
+        }
+        let avoided_maps = self
+            .avoided_maps
+            .as_ref()
+            .expect("Error when accessing avoided maps");
+        Ok(avoided_maps
+            .find(
+                map,
+                self.match_mode,
+                self.case_insensitive,
+                self.map_name_suffixes(),
+            )
+            .map(String::from))
+    }
+
+    /// Suffixes removed from map names before comparing them.
+    fn map_name_suffixes(&self) -> &[String] {
+        if !self.normalize_map_names {
+            return &[];
+        }
+        match &self.map_name_suffixes {
+            Some(suffixes) => suffixes,
+            None => &DEFAULT_MAP_NAME_SUFFIXES,
+        }
+    }
+
+    /// Checks whether a map of `tier` reaches `min_tier`.
+    fn is_avoided_tier(&self, tier: Option<u32>) -> bool {
+        match (tier, self.min_tier) {
+            (Some(tier), Some(min_tier)) => tier >= min_tier,
+            _ => false,
+        }
+    }
+
+    /// Finds the index of the first map group listing `map`.
+    fn map_group(&mut self, map: &str) -> Result<Option<usize>, AtlasError> {
+        if self
+            .map_groups
+            .iter()
+            .any(|group| group.avoided_maps.is_none())
+        {
+            self.compile_avoided_maps()?;
+        }
+        let suffixes = self.map_name_suffixes();
+        for (i, group) in self.map_groups.iter().enumerate() {
+            let avoided_maps = group
+                .avoided_maps
+                .as_ref()
+                .expect("Error when accessing avoided maps");
+            if avoided_maps.contains(map, self.match_mode, self.case_insensitive, suffixes) {
+                return Ok(Some(i));
             }
         }
+        Ok(None)
     }
-    Ok(())
-}
 
-fn notify_map() -> Result<(), AtlasError> {
-    Notification::new()
-        .summary("brickatlas map")
-        .body("Do <u><b>NOT</b></u> complete map!")
-        .timeout(Timeout::Milliseconds(5000))
-        .urgency(Urgency::Critical)
-        .show()?;
-    Ok(())
-}
+    /// Creates a configuration watching `logfiles` for entering `maps`.
+    ///
+    /// The regexes are validated and compiled right away, everything else
+    /// starts out with its default and can be changed with the `with_`
+    /// methods.
+    pub fn new(
+        logfiles: Vec<String>,
+        maps: Vec<String>,
+        maps_regex: &str,
+        buy_regex: &str,
+    ) -> Result<Config, AtlasError> {
+        let mut config = Config {
+            logfiles,
+            maps,
+            maps_regex: String::from(maps_regex),
+            buy_regex: String::from(buy_regex),
+            ..Default::default()
+        };
+        config.validate()?;
+        config.compile_avoided_maps()?;
+        config.compiled_maps_regex()?;
+        config.compiled_buy_regexes()?;
+        Ok(config)
+    }
 
-fn notify_buyer(
-    buyer: &str,
-    object: &str,
-    price: &str,
-    league: &str,
-    location: &str,
-) -> Result<(), AtlasError> {
-    Notification::new()
-        .summary("brickatlas buyer")
-        .body(
-            format!(
-                r"buyer: <b>{}</b>
-object: <b>{}</b>
-price: <b>{}</b>
-league: <b>{}</b>
-location: <b>{}</b>",
-                buyer, object, price, league, location
-            )
-            .as_str(),
-        )
-        .timeout(Timeout::Milliseconds(5000))
-        .show()?;
-    Ok(())
-}
+    /// Compares map names ignoring their case.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Config {
+        self.case_insensitive = case_insensitive;
+        self.avoided_maps = None;
+        for group in &mut self.map_groups {
+            group.avoided_maps = None;
+        }
+        self
+    }
 
-/// Runs the application given a certain configuration.
-pub fn run(config: &mut Config) -> Result<(), AtlasError> {
-    if !Path::new(&config.logfile).exists() {
-        return Err(AtlasError::ConfigError(format!(
-            "watchfile ({}) doesn't exist",
-            &config.logfile
-        )));
+    /// Alerts on maps not listed instead of the listed ones.
+    pub fn with_invert_maps(mut self, invert_maps: bool) -> Config {
+        self.invert_maps = invert_maps;
+        self
     }
 
-    let (tx, rx) = mpsc::channel();
-    let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())?;
-    watcher.watch(&config.logfile.as_ref(), RecursiveMode::NonRecursive)?;
+    /// Prints events instead of showing notifications.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Config {
+        self.dry_run = dry_run;
+        self
+    }
 
-    let f = File::open(&config.logfile)?;
-    let mut f = BufReader::new(f);
-    f.seek(SeekFrom::End(0))?;
+    /// Appends every entered map to `history_file` as JSON line.
+    pub fn with_history_file(mut self, history_file: &str) -> Config {
+        self.history_file = Some(String::from(history_file));
+        self
+    }
 
-    for res in rx {
-        handle_event(res?, config, &mut f)?;
+    /// Posts map and buyer events to `webhook_url` as JSON.
+    pub fn with_webhook_url(mut self, webhook_url: &str) -> Config {
+        self.webhook_url = Some(String::from(webhook_url));
+        self
     }
+
+    /// Only notifies about offers worth at least `min_price`, valued with
+    /// `exchange_rates` of the currencies in lower case.
+    pub fn with_min_price(
+        mut self,
+        min_price: f64,
+        exchange_rates: HashMap<String, f64>,
+    ) -> Config {
+        self.min_price = Some(min_price);
+        self.exchange_rates = exchange_rates;
+        self
+    }
+
+    /// Collects file changes for `debounce_ms` milliseconds before handling
+    /// them.
+    pub fn with_debounce_ms(mut self, debounce_ms: u64) -> Config {
+        self.debounce_ms = Some(debounce_ms);
+        self
+    }
+
+    /// Tells the time with `clock` instead of the system's clock.
+    ///
+    /// Everything depending on the time goes by it, so debouncing, retrying
+    /// and waiting for log files only move on as the clock does.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Config {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Parse configuration from a toml file.
+    ///
+    /// Files ending in `.json` are parsed as JSON instead.
+    pub fn new_from_file(file: &str) -> Result<Config, AtlasError> {
+        info!("reading configuration from {}", file);
+        let is_json = Path::new(file)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let directory = Path::new(file).parent().unwrap_or_else(|| Path::new(""));
+        let mut config = Self::read_config(File::open(file)?, is_json, directory)?;
+        config.config_file = Some(absolute_path(file)?);
+        Ok(config)
+    }
+
+    /// Parse configuration in toml from `reader`.
+    ///
+    /// `maps_file` and `maps_json` are relative to the current directory.
+    pub fn new_from_reader<R: Read>(reader: R) -> Result<Config, AtlasError> {
+        Self::read_config(reader, false, Path::new(""))
+    }
+
+    /// Parses the configuration from `reader`, files it names are relative
+    /// to `directory`.
+    fn read_config<R: Read>(
+        mut reader: R,
+        is_json: bool,
+        directory: &Path,
+    ) -> Result<Config, AtlasError> {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+        let mut config: Config = if is_json {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        config.expand_paths()?;
+        if !config.logfile.is_empty() {
+            let logfile = std::mem::take(&mut config.logfile);
+            config.logfiles.insert(0, logfile);
+        }
+        if !config.maps_file.is_empty() {
+            let maps_file = directory.join(&config.maps_file);
+            config.maps.extend(read_maps_file(&maps_file)?);
+        }
+        if !config.maps_json.is_empty() {
+            let maps_json = directory.join(&config.maps_json);
+            config.maps.extend(read_maps_json(&maps_json)?);
+        }
+        config.validate()?;
+        config.compile_avoided_maps()?;
+        Ok(config)
+    }
+
+    /// Expands the environment variables in the configured paths.
+    fn expand_paths(&mut self) -> Result<(), AtlasError> {
+        self.logfile = expand_env(&self.logfile)?;
+        for logfile in &mut self.logfiles {
+            *logfile = expand_env(logfile)?;
+        }
+        self.maps_file = expand_env(&self.maps_file)?;
+        self.maps_json = expand_env(&self.maps_json)?;
+        for path in [
+            &mut self.history_file,
+            &mut self.buyer_log_file,
+            &mut self.notification_file,
+            &mut self.state_file,
+            &mut self.event_socket,
+            &mut self.sound,
+            &mut self.map_icon,
+            &mut self.buyer_icon,
+        ]
+        .iter_mut()
+        .filter_map(|path| path.as_mut())
+        {
+            *path = expand_env(path)?;
+        }
+        for group in &mut self.map_groups {
+            if let Some(sound) = &mut group.sound {
+                *sound = expand_env(sound)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rereads the configuration file this configuration was loaded from.
+    ///
+    /// The watched log files, the command line arguments and the clock are
+    /// kept, everything else is replaced by the contents of the file. If the
+    /// file can't be used the current configuration stays in place and a
+    /// warning is printed.
+    fn reload(&mut self) {
+        let file = match &self.config_file {
+            Some(file) => file.clone(),
+            None => return,
+        };
+        match Self::new_from_file(
+            file.to_str()
+                .expect("Error while extracting config file string for reloading"),
+        ) {
+            Ok(mut config) => {
+                // same order as when starting, see new_from_args
+                config.arg_overrides = self.arg_overrides.clone();
+                config.apply_arg_overrides();
+                config.apply_env_fallbacks();
+                if let Err(e) = config
+                    .validate()
+                    .and_then(|_| config.compile_avoided_maps())
+                {
+                    warn!(
+                        "error while reloading configuration, keeping the old one: {}",
+                        e
+                    );
+                    return;
+                }
+                config.logfiles = std::mem::take(&mut self.logfiles);
+                config.clock = self.clock.take();
+                *self = config;
+            }
+            Err(e) => warn!(
+                "error while reloading configuration, keeping the old one: {}",
+                e
+            ),
+        }
+    }
+
+    /// Formats the configuration as toml, for example to check which
+    /// settings are used.
+    pub fn to_toml(&self) -> Result<String, AtlasError> {
+        // going through a value puts the tables after the plain settings
+        Ok(toml::to_string(&toml::Value::try_from(self)?)?)
+    }
+
+    /// Time file changes are collected before being handled.
+    fn debounce(&self) -> Duration {
+        Duration::from_millis(
+            self.debounce_ms
+                .unwrap_or(DEFAULT_DEBOUNCE_MS)
+                .max(MIN_DEBOUNCE_MS),
+        )
+    }
+
+    /// Checks whether `buyer` is one of the `ignored_buyers`.
+    fn is_ignored_buyer(&mut self, buyer: &str) -> bool {
+        let Self {
+            ignored_buyers,
+            ignored_buyers_normalized,
+            ..
+        } = self;
+        let ignored = ignored_buyers_normalized
+            .get_or_insert_with(|| ignored_buyers.iter().map(|b| b.to_lowercase()).collect());
+        ignored.contains(&buyer.to_lowercase())
+    }
+
+    /// Checks whether the offered `object` lacks all of the `rarity`
+    /// keywords.
+    ///
+    /// Without keywords objects are never lacking them.
+    fn lacks_rarity(&self, object: &str) -> bool {
+        if self.rarity.is_empty() {
+            return false;
+        }
+        let object = object.to_lowercase();
+        !self
+            .rarity
+            .iter()
+            .any(|keyword| object.contains(&keyword.to_lowercase()))
+    }
+
+    /// Checks whether the offered `price` is worth less than `min_price`.
+    ///
+    /// Prices which can't be valued are never below, so they're still
+    /// notified about.
+    fn is_below_min_price(&self, price: &str) -> bool {
+        let min_price = match self.min_price {
+            Some(min_price) => min_price,
+            None => return false,
+        };
+        let value = parse_price(price).and_then(|(amount, currency)| {
+            self.exchange_rates
+                .get(&currency.to_lowercase())
+                .map(|rate| amount * rate)
+        });
+        match value {
+            Some(value) => value < min_price,
+            None => {
+                warn!("can't value price {}, notifying anyway", price);
+                false
+            }
+        }
+    }
+
+    /// Lines read at most whenever a log file changed, unlimited with
+    /// `once` to read the whole files.
+    fn max_lines_per_event(&self) -> usize {
+        if self.once {
+            return usize::MAX;
+        }
+        self.max_lines_per_event
+            .unwrap_or(DEFAULT_MAX_LINES_PER_EVENT)
+    }
+
+    /// Notifier showing map alerts wherever `map_notifiers` lists, buy
+    /// offers wherever `buyer_notifiers` lists and all other events wherever
+    /// `notifiers` lists.
+    fn notifier(&self) -> Result<RoutingNotifier, AtlasError> {
+        Ok(RoutingNotifier {
+            map: self.notifiers_of(&self.map_notifiers)?,
+            buyer: self.notifiers_of(&self.buyer_notifiers)?,
+            other: self.notifiers_of(&[])?,
+        })
+    }
+
+    /// Returns `kinds`, falling back to `notifiers` if empty.
+    ///
+    /// `None` is returned if neither is configured.
+    fn configured_notifiers<'a>(&'a self, kinds: &'a [NotifierKind]) -> Option<&'a [NotifierKind]> {
+        if !kinds.is_empty() {
+            Some(kinds)
+        } else if !self.notifiers.is_empty() {
+            Some(&self.notifiers)
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether events shown wherever `kinds` lists are posted to
+    /// `webhook_url`, which they are without any notifiers configured.
+    fn posts_to_webhook(&self, kinds: &[NotifierKind]) -> bool {
+        self.webhook_url.is_some()
+            && self
+                .configured_notifiers(kinds)
+                .is_none_or(|kinds| kinds.contains(&NotifierKind::Webhook))
+    }
+
+    /// Notifier showing events wherever `kinds` lists, falling back to
+    /// `notifiers` if empty.
+    ///
+    /// Without desktop notifications compiled in, events are printed instead.
+    /// Posting to the webhook is left to the session, see
+    /// [`Config::posts_to_webhook`].
+    fn notifiers_of(&self, kinds: &[NotifierKind]) -> Result<CompositeNotifier, AtlasError> {
+        let kinds = self
+            .configured_notifiers(kinds)
+            .unwrap_or(&[DEFAULT_NOTIFIER]);
+        let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+        for kind in kinds {
+            match kind {
+                #[cfg(feature = "desktop-notifications")]
+                NotifierKind::Desktop => notifiers.push(Box::new(DesktopNotifier::default())),
+                #[cfg(not(feature = "desktop-notifications"))]
+                NotifierKind::Desktop => {
+                    warn!("built without desktop notifications, printing events instead");
+                    notifiers.push(Box::new(StdoutNotifier));
+                }
+                NotifierKind::Stdout => notifiers.push(Box::new(StdoutNotifier)),
+                NotifierKind::File => {
+                    let file = self.notification_file.as_deref().ok_or_else(|| {
+                        AtlasError::ConfigError(String::from(
+                            "the file notifier needs notification_file",
+                        ))
+                    })?;
+                    notifiers.push(Box::new(FileNotifier::open(Path::new(file))?));
+                }
+                NotifierKind::Webhook | NotifierKind::None => {}
+            }
+        }
+        Ok(CompositeNotifier::new(notifiers))
+    }
+
+    /// Time between checks of the log files in poll mode.
+    fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.unwrap_or(DEFAULT_POLL_INTERVAL_MS))
+    }
+
+    /// Time notifications stay snoozed.
+    fn snooze_duration(&self) -> Duration {
+        Duration::from_secs(60 * self.snooze_minutes.unwrap_or(DEFAULT_SNOOZE_MINUTES))
+    }
+
+    /// Formats `price` for notifications, using the configured currency
+    /// alias.
+    ///
+    /// Prices with unknown currencies are returned unchanged.
+    fn display_price<'a>(&self, price: &'a str) -> Cow<'a, str> {
+        let mut parts = price.split_whitespace();
+        if let (Some(amount), Some(currency), None) = (parts.next(), parts.next(), parts.next()) {
+            if let Some(alias) = self.currency_aliases.get(&currency.to_lowercase()) {
+                return Cow::Owned(format!("{} {}", amount, alias));
+            }
+        }
+        Cow::Borrowed(price)
+    }
+
+    /// Time at least between two notifications, if limited.
+    fn min_notification_interval(&self) -> Option<Duration> {
+        self.min_notification_interval_ms.map(Duration::from_millis)
+    }
+
+    /// Time between heartbeats, if they're enabled.
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_secs.map(Duration::from_secs)
+    }
+
+    /// Clock telling the time.
+    fn clock(&self) -> &dyn Clock {
+        self.clock.as_deref().unwrap_or(&SystemClock)
+    }
+
+    /// Time repeats of an event are suppressed.
+    fn dedup_window(&self) -> Duration {
+        Duration::from_millis(self.dedup_window_ms.unwrap_or(DEFAULT_DEDUP_WINDOW_MS))
+    }
+
+    /// Checks whether an event at `timestamp` is older than
+    /// `max_event_age_secs`.
+    fn is_too_old(&self, timestamp: Option<NaiveDateTime>) -> bool {
+        match (self.max_event_age_secs, timestamp) {
+            (Some(max_age), Some(timestamp)) => {
+                let age = self.clock().local() - timestamp;
+                age.num_seconds() > i64::try_from(max_age).unwrap_or(i64::MAX)
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks whether `event` concerns the configuration file.
+    fn is_config_event(&self, event: &Event) -> bool {
+        match &self.config_file {
+            Some(file) => event.paths.contains(file),
+            None => false,
+        }
+    }
+}
+
+lazy_static! {
+    /// Environment variables in paths, like `$HOME`, `${HOME}` or `%APPDATA%`.
+    static ref ENV_VAR_REGEX: Regex =
+        Regex::new(r"\$\{(\w+)\}|\$(\w+)|%(\w+)%").expect("invalid ENV_VAR_REGEX");
+}
+
+/// Replaces the environment variables in `path` by their values.
+///
+/// Fails if a variable isn't set, instead of producing a broken path.
+fn expand_env(path: &str) -> Result<String, AtlasError> {
+    let mut expanded = String::new();
+    let mut last = 0;
+    for cap in ENV_VAR_REGEX.captures_iter(path) {
+        let whole = cap.get(0).expect("match without group 0");
+        let name = (1..=3)
+            .find_map(|i| cap.get(i))
+            .expect("match without variable name")
+            .as_str();
+        let value = std::env::var(name).map_err(|_| {
+            AtlasError::ConfigError(format!(
+                "environment variable {} used in {} isn't set",
+                name, path
+            ))
+        })?;
+        expanded.push_str(&path[last..whole.start()]);
+        expanded.push_str(&value);
+        last = whole.end();
+    }
+    expanded.push_str(&path[last..]);
+    Ok(expanded)
+}
+
+/// Sets up logging, every `verbosity` level shows more details.
+fn init_logging(verbosity: u64) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    // a program embedding the library may have set up logging already
+    if let Err(e) = env_logger::Builder::new()
+        .filter_module("brickatlas", level)
+        .try_init()
+    {
+        debug!("keeping the logger already set up: {}", e);
+    }
+}
+
+/// Makes `path` absolute, as the paths reported by the watcher are.
+fn absolute_path(path: &str) -> Result<PathBuf, AtlasError> {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+/// Reads the map names from `file`.
+///
+/// Empty lines and lines starting with `#` are skipped.
+fn read_maps_file(file: &Path) -> Result<Vec<String>, AtlasError> {
+    Ok(fs::read_to_string(file)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// A map listed in `maps_json`.
+#[derive(Debug, Deserialize)]
+struct MapEntry {
+    name: String,
+    /// Whether to avoid the map, further fields like its tier are ignored.
+    #[serde(default)]
+    avoid: bool,
+}
+
+/// Reads the names of the maps to avoid from the JSON `file`.
+fn read_maps_json(file: &Path) -> Result<Vec<String>, AtlasError> {
+    let entries: Vec<MapEntry> = serde_json::from_str(&fs::read_to_string(file)?)?;
+    Ok(entries
+        .into_iter()
+        .filter(|entry| entry.avoid)
+        .map(|entry| entry.name)
+        .collect())
+}
+
+/// Prefix of entries in `maps` which are regexes.
+const MAP_PATTERN_PREFIX: &str = "re:";
+
+/// The maps to avoid prepared for comparisons.
+#[derive(Debug)]
+struct AvoidedMaps {
+    /// Plain entries with their normalized names.
+    names: Vec<(String, String)>,
+    /// `re:` entries with their compiled regexes.
+    patterns: Vec<(String, Regex)>,
+}
+
+impl AvoidedMaps {
+    fn new(
+        maps: &[String],
+        case_insensitive: bool,
+        suffixes: &[String],
+    ) -> Result<AvoidedMaps, AtlasError> {
+        let mut names = Vec::new();
+        let mut patterns = Vec::new();
+        for map in maps {
+            match map.strip_prefix(MAP_PATTERN_PREFIX) {
+                Some(pattern) => patterns.push((
+                    map.clone(),
+                    RegexBuilder::new(pattern)
+                        .case_insensitive(case_insensitive)
+                        .build()?,
+                )),
+                None => names.push((
+                    map.clone(),
+                    normalize_map(map, case_insensitive, suffixes).into_owned(),
+                )),
+            }
+        }
+        Ok(AvoidedMaps { names, patterns })
+    }
+
+    /// Checks whether `map` is one of the maps.
+    fn contains(
+        &self,
+        map: &str,
+        match_mode: MatchMode,
+        case_insensitive: bool,
+        suffixes: &[String],
+    ) -> bool {
+        self.find(map, match_mode, case_insensitive, suffixes)
+            .is_some()
+    }
+
+    /// Returns the first entry `map` matches.
+    ///
+    /// Plain entries are checked before the `re:` entries, each in the
+    /// order they are listed.
+    fn find(
+        &self,
+        map: &str,
+        match_mode: MatchMode,
+        case_insensitive: bool,
+        suffixes: &[String],
+    ) -> Option<&str> {
+        let normalized = normalize_map(map, case_insensitive, suffixes);
+        let listed = self.names.iter().find(|(_, name)| match match_mode {
+            MatchMode::Exact => name.as_str() == normalized,
+            MatchMode::Contains => normalized.contains(name.as_str()),
+        });
+        let listed = listed.map(|(entry, _)| entry.as_str());
+        listed.or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(_, pattern)| pattern.is_match(map))
+                .map(|(entry, _)| entry.as_str())
+        })
+    }
+}
+
+lazy_static! {
+    /// Default suffixes removed from map names with `normalize_map_names`.
+    static ref DEFAULT_MAP_NAME_SUFFIXES: Vec<String> = vec![String::from(" Map")];
+}
+
+/// Brings a map name into the form used for comparisons.
+///
+/// The first of `suffixes` the name ends with is removed, unless nothing
+/// would be left.
+fn normalize_map<'a>(map: &'a str, case_insensitive: bool, suffixes: &[String]) -> Cow<'a, str> {
+    let mut map = if case_insensitive {
+        Cow::Owned(map.to_lowercase())
+    } else {
+        Cow::Borrowed(map)
+    };
+    for suffix in suffixes {
+        let suffix = if case_insensitive {
+            Cow::Owned(suffix.to_lowercase())
+        } else {
+            Cow::Borrowed(suffix.as_str())
+        };
+        if map.len() > suffix.len() && map.ends_with(suffix.as_ref()) {
+            let len = map.len() - suffix.len();
+            map = match map {
+                Cow::Borrowed(map) => Cow::Borrowed(&map[..len]),
+                Cow::Owned(mut map) => {
+                    map.truncate(len);
+                    Cow::Owned(map)
+                }
+            };
+            break;
+        }
+    }
+    map
+}
+
+/// Returns the compiled regex, compiling `source` on first use.
+fn compile_regex<'a>(
+    source: &str,
+    case_insensitive: bool,
+    compiled: &'a mut Option<Regex>,
+) -> Result<&'a Regex, AtlasError> {
+    if compiled.is_none() {
+        *compiled = Some(
+            RegexBuilder::new(source)
+                .case_insensitive(case_insensitive)
+                .build()?,
+        );
+    }
+    Ok(compiled
+        .as_ref()
+        .expect("Error when accessing compiled regex"))
+}
+
+/// Returns the compiled regex if `source` isn't empty.
+fn compile_optional_regex<'a>(
+    source: &str,
+    compiled: &'a mut Option<Regex>,
+) -> Result<Option<&'a Regex>, AtlasError> {
+    if source.is_empty() {
+        Ok(None)
+    } else {
+        compile_regex(source, false, compiled).map(Some)
+    }
+}
+
+/// Checks that the regex `name` compiles and contains all named `groups`.
+fn validate_regex(name: &str, source: &str, groups: &[&str]) -> Result<(), AtlasError> {
+    if source.is_empty() {
+        return Err(AtlasError::ConfigError(format!("{} is missing", name)));
+    }
+    let regex = Regex::new(source)?;
+    for group in groups {
+        if !regex.capture_names().any(|n| n == Some(group)) {
+            return Err(AtlasError::ConfigError(format!(
+                "{} is missing the named group {}",
+                name, group
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Position a log file was read up to, saved in `state_file`.
+#[derive(Debug, Deserialize, Serialize)]
+struct SavedPosition {
+    logfile: PathBuf,
+    position: u64,
+}
+
+/// Reads the positions saved in the state `file`, none if it doesn't exist
+/// yet.
+fn load_positions(file: &str) -> Result<HashMap<PathBuf, u64>, AtlasError> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let saved: Vec<SavedPosition> = serde_json::from_str(&contents)?;
+    Ok(saved
+        .into_iter()
+        .map(|saved| (saved.logfile, saved.position))
+        .collect())
+}
+
+/// Saves how far each of `files` was read to the state `file`.
+fn save_positions(file: &str, files: &HashMap<PathBuf, LogFile>) -> Result<(), AtlasError> {
+    let saved: Vec<_> = files
+        .iter()
+        .map(|(path, logfile)| SavedPosition {
+            logfile: path.clone(),
+            position: logfile.position,
+        })
+        .collect();
+    fs::write(file, serde_json::to_string(&saved)?)?;
+    Ok(())
+}
+
+/// A watched log file and how far it was read.
+struct LogFile {
+    reader: BufReader<File>,
+    /// End of the last complete line read, a trailing partial line is read
+    /// again once its newline arrived.
+    position: u64,
+}
+
+impl LogFile {
+    /// Opens `path` to read the lines appended from now on.
+    fn open(path: &str) -> Result<LogFile, AtlasError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let position = reader.seek(SeekFrom::End(0))?;
+        Ok(LogFile { reader, position })
+    }
+
+    /// Opens `path` to read all of its lines, for files replacing the one
+    /// watched so far.
+    fn reopen(path: &Path) -> Result<LogFile, AtlasError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(LogFile {
+            reader,
+            position: 0,
+        })
+    }
+
+    /// Continues at `position` read up to by a previous run, or at the
+    /// start if the file got shorter since.
+    fn restore_position(&mut self, position: u64) -> Result<(), AtlasError> {
+        let len = self.reader.get_ref().metadata()?.len();
+        self.position = if len < position { 0 } else { position };
+        Ok(())
+    }
+
+    /// Moves back to the start of the last `count` lines.
+    fn rewind_lines(&mut self, count: usize) -> Result<(), AtlasError> {
+        let file = self.reader.get_mut();
+        let end = file.seek(SeekFrom::End(0))?;
+        let mut start = if count == 0 { end } else { 0 };
+        let mut found = 0;
+        let mut chunk = [0; 4096];
+        let mut chunk_end = end;
+        'search: while chunk_end > 0 && count > 0 {
+            let chunk_start = chunk_end.saturating_sub(chunk.len() as u64);
+            let chunk = &mut chunk[..(chunk_end - chunk_start) as usize];
+            file.seek(SeekFrom::Start(chunk_start))?;
+            file.read_exact(chunk)?;
+            for (i, byte) in chunk.iter().enumerate().rev() {
+                let offset = chunk_start + i as u64;
+                // the newline ending the last line doesn't start a line
+                if *byte == b'\n' && offset + 1 < end {
+                    found += 1;
+                    if found == count {
+                        start = offset + 1;
+                        break 'search;
+                    }
+                }
+            }
+            chunk_end = chunk_start;
+        }
+        self.position = self.reader.seek(SeekFrom::Start(start))?;
+        Ok(())
+    }
+
+    /// Starts reading from the beginning again if the file was truncated
+    /// below the position read so far.
+    fn rewind_if_truncated(&mut self) -> Result<(), AtlasError> {
+        if self.reader.get_ref().metadata()?.len() < self.position {
+            self.position = 0;
+        }
+        Ok(())
+    }
+
+    /// Moves the reader to the position read so far, so reading never
+    /// depends on what the reader buffered before.
+    fn seek_to_position(&mut self) -> Result<(), AtlasError> {
+        self.reader.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+
+    /// Continues reading at the current end of the file.
+    fn skip_to_end(&mut self) -> Result<(), AtlasError> {
+        self.position = self.reader.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Checks whether bytes were appended after the position read so far.
+    fn has_new_bytes(&self) -> Result<bool, AtlasError> {
+        Ok(self.reader.get_ref().metadata()?.len() > self.position)
+    }
+
+    /// Number of the line starting at the position read so far, if the
+    /// lines before it can be counted.
+    ///
+    /// The lines are only counted when needed, which is once reading failed.
+    fn line_number(&mut self) -> Option<u64> {
+        let file = self.reader.get_mut();
+        file.seek(SeekFrom::Start(0)).ok()?;
+        let mut before = BufReader::new(Read::by_ref(file).take(self.position));
+        let mut lines = 1;
+        loop {
+            let buf = before.fill_buf().ok()?;
+            if buf.is_empty() {
+                return Some(lines);
+            }
+            lines += buf.iter().filter(|byte| **byte == b'\n').count() as u64;
+            let len = buf.len();
+            before.consume(len);
+        }
+    }
+}
+
+/// End of the names of hideouts.
+const HIDEOUT_SUFFIX: &str = "Hideout";
+
+/// Default minutes notifications stay snoozed.
+const DEFAULT_SNOOZE_MINUTES: u64 = 5;
+
+/// Log of buy offers, moved aside once it grows too large.
+#[derive(Debug)]
+struct BuyerLog {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to `file` so far.
+    len: u64,
+    /// Bytes after which the log is rotated, if limited.
+    max_len: Option<u64>,
+}
+
+impl BuyerLog {
+    /// Opens the log at `path` to append to it.
+    fn open(path: &Path, max_len: Option<u64>) -> Result<BuyerLog, AtlasError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(BuyerLog {
+            path: path.to_path_buf(),
+            file,
+            len,
+            max_len,
+        })
+    }
+
+    /// Appends `line`, first moving the log to `.1` if it would grow beyond
+    /// its limit.
+    fn append(&mut self, line: &str) -> Result<(), AtlasError> {
+        let size = line.len() as u64 + 1;
+        if self
+            .max_len
+            .is_some_and(|max_len| self.len > 0 && self.len + size > max_len)
+        {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, &rotated)?;
+            *self = BuyerLog::open(&self.path, self.max_len)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.len += size;
+        Ok(())
+    }
+}
+
+/// Unix socket streaming events as JSON lines to connected clients.
+#[cfg(unix)]
+#[derive(Debug)]
+struct EventSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+}
+
+#[cfg(unix)]
+impl EventSocket {
+    /// Listens at `path`, replacing a socket left over from an earlier run.
+    fn bind(path: &Path) -> Result<EventSocket, AtlasError> {
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        info!("streaming events to {}", path.display());
+        Ok(EventSocket {
+            path: path.to_path_buf(),
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Writes `event` to all connected clients.
+    ///
+    /// Clients which can't keep up or disconnected are dropped, so watching
+    /// is never blocked.
+    fn send(&mut self, event: &serde_json::Value) {
+        loop {
+            match self.listener.accept() {
+                Ok((client, _)) => match client.set_nonblocking(true) {
+                    Ok(()) => self.clients.push(client),
+                    Err(e) => warn!("error while accepting event socket client: {}", e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("error while accepting event socket client: {}", e);
+                    break;
+                }
+            }
+        }
+        let line = format!("{}\n", event);
+        self.clients
+            .retain_mut(|client| match client.write_all(line.as_bytes()) {
+                Ok(()) => true,
+                Err(e) => {
+                    debug!("dropping event socket client: {}", e);
+                    false
+                }
+            });
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EventSocket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("error while removing {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Counts what happened while running.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RunStats {
+    /// Lines read from the log files.
+    pub lines_read: u64,
+    /// Bytes read from the log files.
+    pub bytes_read: u64,
+    /// Events read from the log files, leaving out too old and repeated
+    /// ones.
+    pub events_read: u64,
+    /// Entered maps matched by `maps_regex`.
+    pub map_matches: u64,
+    /// Buy offers matched by the buy regexes.
+    pub buyer_matches: u64,
+    /// Alerts shown for entered maps.
+    pub map_alerts: u64,
+    /// Notifications shown for buy offers.
+    pub buyer_notifications: u64,
+    /// Notifications which couldn't be shown.
+    pub notification_errors: u64,
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read {} lines with {} map alerts and {} buyer notifications",
+            self.lines_read, self.map_alerts, self.buyer_notifications
+        )
+    }
+}
+
+/// Tells the time to everything depending on it.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current instant, for measuring intervals.
+    fn now(&self) -> Instant;
+
+    /// Returns the current local time, for comparing with log timestamps.
+    fn local(&self) -> NaiveDateTime;
+
+    /// Waits for `duration`.
+    fn sleep(&self, duration: Duration);
+}
+
+/// Tells the time of the system.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Tells a time only moving when told to, for checking time dependent
+/// behaviour in tests.
+///
+/// Sleeping moves the time on instead of waiting.
+#[cfg(any(test, feature = "recording"))]
+#[derive(Debug)]
+pub struct MockClock {
+    time: Mutex<(Instant, NaiveDateTime)>,
+}
+
+#[cfg(any(test, feature = "recording"))]
+impl MockClock {
+    /// Starts the clock at the local time `local`.
+    pub fn new(local: NaiveDateTime) -> MockClock {
+        MockClock {
+            time: Mutex::new((Instant::now(), local)),
+        }
+    }
+
+    /// Moves the time on by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.time.lock().expect("Error while locking mock clock");
+        time.0 += duration;
+        time.1 += chrono::Duration::from_std(duration).expect("Error while advancing mock clock");
+    }
+}
+
+#[cfg(any(test, feature = "recording"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.time.lock().expect("Error while locking mock clock").0
+    }
+
+    fn local(&self) -> NaiveDateTime {
+        self.time.lock().expect("Error while locking mock clock").1
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// State kept while running.
+#[derive(Debug, Default)]
+struct Session {
+    stats: RunStats,
+    /// Opened `history_file` entered maps are appended to.
+    history: Option<File>,
+    /// Opened `buyer_log_file` buy offers are appended to.
+    buyer_log: Option<BuyerLog>,
+    /// When events were last handled, to suppress repeats.
+    last_events: HashMap<LogEvent, Instant>,
+    /// Modifiers read by `mods_regex` with the number of their line.
+    recent_mods: VecDeque<(u64, String)>,
+    /// When watching was last told to go on, for `heartbeat_secs`.
+    last_heartbeat: Option<Instant>,
+    /// When the last notification was shown, to keep
+    /// `min_notification_interval_ms` between them.
+    last_notified: Option<Instant>,
+    /// Set while notifications are snoozed, toggled from stdin.
+    snoozed: Arc<AtomicBool>,
+    /// When the current snooze started.
+    snoozed_since: Option<Instant>,
+    /// Whether the last entered zone was a hideout.
+    in_hideout: bool,
+    /// Character played and its league, if known.
+    character: Option<(String, Option<String>)>,
+    /// Socket map and buyer events are streamed to.
+    #[cfg(unix)]
+    event_socket: Option<EventSocket>,
+    /// Sender posting map and buyer events to `webhook_url`, started on
+    /// first use.
+    webhook: Option<Webhook>,
+}
+
+impl Session {
+    /// Sets up the session for `config`.
+    fn new(config: &Config) -> Result<Session, AtlasError> {
+        let history = match &config.history_file {
+            Some(file) => Some(OpenOptions::new().create(true).append(true).open(file)?),
+            None => None,
+        };
+        let buyer_log = match &config.buyer_log_file {
+            Some(file) => Some(BuyerLog::open(Path::new(file), config.buyer_log_max_bytes)?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let event_socket = match &config.event_socket {
+            Some(path) => Some(EventSocket::bind(Path::new(path))?),
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if config.event_socket.is_some() {
+            warn!("event_socket is only supported on unix, ignoring it");
+        }
+        Ok(Session {
+            history,
+            buyer_log,
+            #[cfg(unix)]
+            event_socket,
+            ..Default::default()
+        })
+    }
+
+    /// Checks whether `event` was already handled within `window`.
+    ///
+    /// Otherwise the event is remembered for the following checks.
+    fn is_repeated(&mut self, event: &LogEvent, window: Duration, now: Instant) -> bool {
+        self.last_events
+            .retain(|_, handled| now.duration_since(*handled) < window);
+        // repeats are written at other times
+        let event = event.without_timestamp();
+        if self.last_events.contains_key(&event) {
+            return true;
+        }
+        self.last_events.insert(event, now);
+        false
+    }
+
+    /// Passes `event` on to the event socket, and to the webhook if the
+    /// event is routed there by `kinds`.
+    fn publish(&mut self, config: &Config, kinds: &[NotifierKind], event: serde_json::Value) {
+        #[cfg(unix)]
+        if let Some(socket) = &mut self.event_socket {
+            socket.send(&event);
+        }
+        if !config.posts_to_webhook(kinds) {
+            return;
+        }
+        if let Some(url) = &config.webhook_url {
+            self.webhook
+                .get_or_insert_with(Webhook::start)
+                .send(url, event);
+        }
+    }
+
+    /// Counts the `result` of showing a notification.
+    ///
+    /// Failures are only logged to not interrupt watching, `true` is returned
+    /// if the notification was shown.
+    fn notified(&mut self, result: Result<(), AtlasError>) -> bool {
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("error while notifying: {}", e);
+                self.stats.notification_errors += 1;
+                false
+            }
+        }
+    }
+
+    /// Shows a notification with `notify`, keeping the
+    /// `min_notification_interval_ms` to the previous one.
+    ///
+    /// Returns whether it was shown, which notifications dropped by the limit
+    /// or failing weren't.
+    fn notify<F>(&mut self, config: &Config, notify: F) -> bool
+    where
+        F: FnOnce() -> Result<(), AtlasError>,
+    {
+        if let (Some(last), Some(interval)) =
+            (self.last_notified, config.min_notification_interval())
+        {
+            let elapsed = config.clock().now().saturating_duration_since(last);
+            let wait = interval.saturating_sub(elapsed);
+            if !wait.is_zero() {
+                match config.notification_limit {
+                    NotificationLimit::Delay => {
+                        debug!("delaying notification by {:?}", wait);
+                        config.clock().sleep(wait);
+                    }
+                    NotificationLimit::Drop => {
+                        info!("dropping notification within min_notification_interval_ms");
+                        return false;
+                    }
+                }
+            }
+        }
+        self.last_notified = Some(config.clock().now());
+        self.notified(notify())
+    }
+
+    /// Remembers the `mods` read from the current line for `window` lines.
+    fn remember_mods(&mut self, mods: String, window: u64) {
+        let line = self.stats.lines_read;
+        self.recent_mods
+            .retain(|(read, _)| line.saturating_sub(*read) < window);
+        self.recent_mods.push_back((line, mods));
+    }
+
+    /// Finds the first of `avoid_mods` among the modifiers read within
+    /// `window` lines.
+    ///
+    /// The modifiers are forgotten, as they belong to the map entered now.
+    fn take_avoided_mod(&mut self, avoid_mods: &[String], window: u64) -> Option<String> {
+        let line = self.stats.lines_read;
+        let avoided = self
+            .recent_mods
+            .iter()
+            .filter(|(read, _)| line.saturating_sub(*read) < window)
+            .find_map(|(_, mods)| {
+                let mods = mods.to_lowercase();
+                avoid_mods
+                    .iter()
+                    .find(|avoided| mods.contains(&avoided.to_lowercase()))
+                    .cloned()
+            });
+        self.recent_mods.clear();
+        avoided
+    }
+
+    /// Remembers that `character` in `league` is played now.
+    ///
+    /// Returns whether another character was played before.
+    fn select_character(&mut self, character: &str, league: Option<&str>) -> bool {
+        let selected = (String::from(character), league.map(String::from));
+        if self.character.as_ref() == Some(&selected) {
+            return false;
+        }
+        self.character = Some(selected);
+        true
+    }
+
+    /// Checks whether notifications are currently snoozed.
+    fn is_snoozed(&self) -> bool {
+        self.snoozed.load(Ordering::SeqCst)
+    }
+
+    /// Follows changes of the snooze and ends it once it's over.
+    ///
+    /// The `notifier` confirms every start and end of a snooze.
+    fn update_snooze(&mut self, config: &Config, notifier: &dyn Notifier) {
+        let now = config.clock().now();
+        match (self.is_snoozed(), self.snoozed_since) {
+            (true, None) => {
+                info!("snoozing notifications");
+                self.snoozed_since = Some(now);
+                let result = notifier.snoozed(config, true);
+                self.notified(result);
+            }
+            (true, Some(since))
+                if now.saturating_duration_since(since) >= config.snooze_duration() =>
+            {
+                info!("snooze is over");
+                self.snoozed.store(false, Ordering::SeqCst);
+                self.snoozed_since = None;
+                let result = notifier.snoozed(config, false);
+                self.notified(result);
+            }
+            (false, Some(_)) => {
+                info!("snooze was ended");
+                self.snoozed_since = None;
+                let result = notifier.snoozed(config, false);
+                self.notified(result);
+            }
+            _ => {}
+        }
+    }
+
+    /// Tells that watching is still going on once `heartbeat_secs` passed
+    /// since the last time.
+    fn heartbeat(&mut self, config: &Config, notifier: &dyn Notifier) {
+        let interval = match config.heartbeat_interval() {
+            Some(interval) => interval,
+            None => return,
+        };
+        let now = config.clock().now();
+        let last = *self.last_heartbeat.get_or_insert(now);
+        if now.saturating_duration_since(last) < interval {
+            return;
+        }
+        self.last_heartbeat = Some(now);
+        info!(
+            "still watching, read {} events so far",
+            self.stats.events_read
+        );
+        if config.heartbeat_notification {
+            let result = notifier.heartbeat(config, &self.stats);
+            self.notified(result);
+        }
+    }
+
+    /// Appends the entered `map` to the history, if one is kept.
+    ///
+    /// Failures are only logged to not interrupt watching.
+    fn record_map(
+        &mut self,
+        config: &Config,
+        timestamp: Option<NaiveDateTime>,
+        map: &str,
+        avoided: bool,
+    ) {
+        if let Some(history) = &mut self.history {
+            let entry = json!({
+                "timestamp": format_timestamp(config.clock(), timestamp),
+                "map": map,
+                "avoided": avoided,
+            });
+            if let Err(e) = writeln!(history, "{}", entry) {
+                warn!("error while writing map history: {}", e);
+            }
+        }
+    }
+
+    /// Appends the buy `offer` to the buyer log, if one is kept.
+    ///
+    /// Failures are only logged to not interrupt watching.
+    fn record_offer(
+        &mut self,
+        config: &Config,
+        timestamp: Option<NaiveDateTime>,
+        offer: &BuyOffer,
+    ) {
+        if let Some(buyer_log) = &mut self.buyer_log {
+            let line = [
+                format_timestamp(config.clock(), timestamp).as_str(),
+                &offer.buyer,
+                &offer.object,
+                &offer.price,
+                &offer.league,
+                &offer.location,
+            ]
+            .join("\t");
+            if let Err(e) = buyer_log.append(&line) {
+                warn!("error while writing buyer log: {}", e);
+            }
+        }
+    }
+}
+
+/// Format of the timestamps at the start of log lines.
+const LOG_TIMESTAMP_FORMAT: &str = "%Y/%m/%d %H:%M:%S";
+/// Format of the timestamps written to the history and webhooks.
+const EVENT_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Parses the timestamp at the start of a log line.
+///
+/// PoE starts its log lines with the local time like `2023/11/05 14:23:01`,
+/// `None` is returned for lines without such a timestamp.
+pub fn parse_timestamp(line: &str) -> Option<NaiveDateTime> {
+    let timestamp = line.get(..19)?;
+    NaiveDateTime::parse_from_str(timestamp, LOG_TIMESTAMP_FORMAT).ok()
+}
+
+/// Continues with the file a followed symlink at `link` points to now, if
+/// it changed from `target`.
+fn follow_symlink(
+    link: &Path,
+    target: &mut PathBuf,
+    watcher: &mut dyn Watcher,
+    config: &mut Config,
+    files: &mut HashMap<PathBuf, LogFile>,
+    session: &mut Session,
+    notifier: &dyn Notifier,
+) -> Result<(), AtlasError> {
+    let new_target = match fs::canonicalize(link) {
+        Ok(new_target) => new_target,
+        Err(e) => {
+            info!("{} doesn't point to a file anymore: {}", link.display(), e);
+            return Ok(());
+        }
+    };
+    if new_target == *target {
+        return Ok(());
+    }
+    info!(
+        "{} now points to {}, following it",
+        link.display(),
+        new_target.display()
+    );
+    watcher.watch(
+        new_target.parent().unwrap_or(&new_target),
+        RecursiveMode::NonRecursive,
+    )?;
+    let mut file = LogFile::reopen(&new_target).map_err(|e| AtlasError::ReadError {
+        path: new_target.clone(),
+        line: None,
+        source: Box::new(e),
+    })?;
+    files.remove(target.as_path());
+    read_new_lines(&new_target, &mut file, config, session, notifier)?;
+    files.insert(new_target.clone(), file);
+    *target = new_target;
+    Ok(())
+}
+
+fn handle_event(
+    event: Event,
+    config: &mut Config,
+    files: &mut HashMap<PathBuf, LogFile>,
+    links: &mut HashMap<PathBuf, PathBuf>,
+    watcher: &mut dyn Watcher,
+    session: &mut Session,
+    notifier: &dyn Notifier,
+) -> Result<(), AtlasError> {
+    if config.is_config_event(&event) {
+        // editors often save by writing a new file and renaming it
+        if event.kind.is_modify() || event.kind.is_create() {
+            config.reload();
+        }
+    } else if event.kind.is_create() || matches!(event.kind, EventKind::Modify(ModifyKind::Name(_)))
+    {
+        for path in &event.paths {
+            if let Some(target) = links.get_mut(path) {
+                follow_symlink(path, target, watcher, config, files, session, notifier)?;
+            }
+        }
+        // the log file was rotated, continue with the file now at its path
+        for path in &event.paths {
+            if let Some(file) = files.get_mut(path) {
+                if path.exists() {
+                    info!("{} was replaced, reopening it", path.display());
+                    *file = LogFile::reopen(path).map_err(|e| AtlasError::ReadError {
+                        path: path.clone(),
+                        line: None,
+                        source: Box::new(e),
+                    })?;
+                    read_new_lines(path, file, config, session, notifier)?;
+                } else {
+                    info!(
+                        "{} was moved away, waiting for it to reappear",
+                        path.display()
+                    );
+                }
+            }
+        }
+    } else if event.kind.is_modify() {
+        for path in &event.paths {
+            if let Some(file) = files.get_mut(path) {
+                read_new_lines(path, file, config, session, notifier)?;
+            }
+        }
+    } else if event.kind.is_remove() {
+        for path in &event.paths {
+            if files.contains_key(path) {
+                info!("{} was removed, waiting for it to reappear", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handles the lines added to `file` since it was last read.
+///
+/// Errors reading tell the file and the position of the line they happened
+/// at, errors handling a line are returned as they are.
+fn read_new_lines(
+    path: &Path,
+    file: &mut LogFile,
+    config: &mut Config,
+    session: &mut Session,
+    notifier: &dyn Notifier,
+) -> Result<(), AtlasError> {
+    read_lines(path, file, config, session, notifier).map_err(|e| AtlasError::ReadError {
+        path: path.to_path_buf(),
+        line: file.line_number(),
+        source: Box::new(e),
+    })?
+}
+
+/// Reads the new lines of `file`, the outer error is one reading and the
+/// inner one handling a line.
+fn read_lines(
+    path: &Path,
+    file: &mut LogFile,
+    config: &mut Config,
+    session: &mut Session,
+    notifier: &dyn Notifier,
+) -> Result<Result<(), AtlasError>, AtlasError> {
+    file.rewind_if_truncated()?;
+    if !file.has_new_bytes()? {
+        trace!("nothing new in {}", path.display());
+        return Ok(Ok(()));
+    }
+    trace!("reading {} from position {}", path.display(), file.position);
+    file.seek_to_position()?;
+    let mut count = 0;
+    let mut buf = Vec::new();
+    loop {
+        let read = file.reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if buf.last() != Some(&b'\n') && !config.once {
+            // the line is still being written, it's read again once complete
+            break;
+        }
+        if count >= config.max_lines_per_event() {
+            warn!(
+                "read {} lines from {} at once, skipping to its end",
+                count,
+                path.display()
+            );
+            file.skip_to_end()?;
+            break;
+        }
+        file.position += read as u64;
+        session.stats.lines_read += 1;
+        session.stats.bytes_read += read as u64;
+        let line = String::from_utf8_lossy(&buf);
+        if let Cow::Owned(_) = line {
+            warn!(
+                "replaced invalid UTF-8 in line read from {}",
+                path.display()
+            );
+        }
+        if let Err(e) = handle_line(
+            line.trim_end_matches(&['\n', '\r'][..]),
+            config,
+            session,
+            notifier,
+        ) {
+            return Ok(Err(e));
+        }
+        count += 1;
+        buf.clear();
+    }
+    trace!(
+        "read {} lines from {} up to position {}",
+        count,
+        path.display(),
+        file.position
+    );
+    Ok(Ok(()))
+}
+
+/// An event found in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LogEvent {
+    /// The player entered a map
+    MapEntered {
+        /// Name of the map
+        name: String,
+        /// Tier of the map, if `maps_regex` captures it
+        tier: Option<u32>,
+        /// When the map was entered, if the line tells
+        timestamp: Option<NaiveDateTime>,
+    },
+    /// Another player whispered to buy an item
+    BuyOffer(BuyOffer),
+    /// A character gained a level
+    LevelUp {
+        /// Name of the character
+        character: String,
+        /// Level the character reached
+        level: String,
+    },
+    /// The player entered one of the configured zones
+    ZoneEntered {
+        /// Name of the zone
+        name: String,
+    },
+    /// AFK mode was turned on or off
+    AfkChanged {
+        /// Whether AFK mode is on now
+        on: bool,
+    },
+    /// The player selected a character
+    CharacterSelected {
+        /// Name of the character
+        character: String,
+        /// League the character plays in, if `character_regex` captures it
+        league: Option<String>,
+    },
+    /// A trade with another player was completed
+    TradeCompleted {
+        /// Player traded with
+        partner: String,
+        /// Items traded, if `trade_regex` captures them
+        items: Option<String>,
+    },
+}
+
+/// A whisper of another player wanting to buy an item.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BuyOffer {
+    /// Player wanting to buy
+    pub buyer: String,
+    /// Item to buy
+    pub object: String,
+    /// Offered price
+    pub price: String,
+    /// League the item is listed in
+    pub league: String,
+    /// Where the item is located, empty if the whisper doesn't tell
+    pub location: String,
+    /// Where the item is in the stash, if the whisper tells
+    pub stash: Option<StashPosition>,
+    /// When the whisper arrived, if the line tells
+    pub timestamp: Option<NaiveDateTime>,
+}
+
+/// Position of an item in the stash, as given by trade whispers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StashPosition {
+    /// Name of the stash tab
+    pub tab: String,
+    /// Column of the item, counted from the left
+    pub left: String,
+    /// Row of the item, counted from the top
+    pub top: String,
+}
+
+impl fmt::Display for StashPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (left {}, top {})", self.tab, self.left, self.top)
+    }
+}
+
+impl LogEvent {
+    /// When the event happened, for events telling it.
+    pub fn timestamp(&self) -> Option<NaiveDateTime> {
+        match self {
+            LogEvent::MapEntered { timestamp, .. } => *timestamp,
+            LogEvent::BuyOffer(offer) => offer.timestamp,
+            _ => None,
+        }
+    }
+
+    /// The event without when it happened, to compare it with repeats.
+    fn without_timestamp(&self) -> LogEvent {
+        let mut event = self.clone();
+        match &mut event {
+            LogEvent::MapEntered { timestamp, .. } => *timestamp = None,
+            LogEvent::BuyOffer(offer) => offer.timestamp = None,
+            _ => {}
+        }
+        event
+    }
+}
+
+impl fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogEvent::MapEntered {
+                name, tier: None, ..
+            } => write!(f, "entered map {}", name),
+            LogEvent::MapEntered {
+                name,
+                tier: Some(tier),
+                ..
+            } => write!(f, "entered map {} (tier {})", name, tier),
+            LogEvent::BuyOffer(offer) => {
+                write!(
+                    f,
+                    "{} wants to buy {} for {} in {}",
+                    offer.buyer, offer.object, offer.price, offer.league
+                )?;
+                if !offer.location.is_empty() {
+                    write!(f, " ({})", offer.location)?;
+                }
+                Ok(())
+            }
+            LogEvent::LevelUp { character, level } => {
+                write!(f, "{} is now level {}", character, level)
+            }
+            LogEvent::ZoneEntered { name } => write!(f, "entered zone {}", name),
+            LogEvent::AfkChanged { on } => {
+                write!(f, "AFK mode is now {}", if *on { "on" } else { "off" })
+            }
+            LogEvent::CharacterSelected {
+                character,
+                league: None,
+            } => write!(f, "selected character {}", character),
+            LogEvent::CharacterSelected {
+                character,
+                league: Some(league),
+            } => write!(f, "selected character {} in {}", character, league),
+            LogEvent::TradeCompleted {
+                partner,
+                items: None,
+            } => write!(f, "traded with {}", partner),
+            LogEvent::TradeCompleted {
+                partner,
+                items: Some(items),
+            } => write!(f, "traded {} with {}", items, partner),
+        }
+    }
+}
+
+/// Parses a single log line into an event using the configured regexes.
+///
+/// Returns `None` if the line doesn't describe an event. Entering one of the
+/// configured zones is reported as such, not as entering a map.
+pub fn parse_line(config: &mut Config, line: &str) -> Result<Option<LogEvent>, AtlasError> {
+    // checked first, as zones are usually entered through the same line as maps
+    if let Some(cap) = config.zones_regex()?.and_then(|r| r.captures(line)) {
+        if let Some(zone) = capture_group(&cap, "zone") {
+            if config.zones.iter().any(|z| z.as_str() == zone) {
+                return Ok(Some(LogEvent::ZoneEntered {
+                    name: String::from(zone),
+                }));
+            }
+        }
+    }
+    if config.map_alerts_enabled.unwrap_or(true) {
+        let cap = config.compiled_maps_regex()?.captures(line);
+        if let Some(map) = cap.as_ref().and_then(|cap| capture_group(cap, "map")) {
+            let tier =
+                cap.and_then(|cap| cap.name("tier"))
+                    .and_then(|tier| match tier.as_str().parse() {
+                        Ok(tier) => Some(tier),
+                        Err(_) => {
+                            debug!("ignoring unparseable tier {}", tier.as_str());
+                            None
+                        }
+                    });
+            return Ok(Some(LogEvent::MapEntered {
+                name: String::from(map),
+                tier,
+                timestamp: parse_timestamp(line),
+            }));
+        }
+    }
+    let offer = if config.buyer_alerts_enabled.unwrap_or(true) {
+        config
+            .compiled_buy_regexes()?
+            .iter()
+            .find_map(|r| r.captures(line).and_then(|cap| buy_offer(&cap, line)))
+    } else {
+        None
+    };
+    if let Some(offer) = offer {
+        let prefix = config
+            .whisper_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_WHISPER_PREFIX);
+        if is_whisper(line, prefix) {
+            return Ok(Some(LogEvent::BuyOffer(offer)));
+        }
+        debug!("ignoring whisper not starting with {}", prefix);
+    }
+    if let Some(cap) = config.level_regex()?.and_then(|r| r.captures(line)) {
+        if let (Some(character), Some(level)) = (
+            capture_group(&cap, "character"),
+            capture_group(&cap, "level"),
+        ) {
+            return Ok(Some(LogEvent::LevelUp {
+                character: String::from(character),
+                level: String::from(level),
+            }));
+        }
+    }
+    if let Some(cap) = config.afk_regex()?.and_then(|r| r.captures(line)) {
+        if let Some(state) = capture_group(&cap, "state") {
+            return Ok(Some(LogEvent::AfkChanged {
+                on: state.eq_ignore_ascii_case("on"),
+            }));
+        }
+    }
+    if let Some(cap) = config.character_regex()?.and_then(|r| r.captures(line)) {
+        if let Some(character) = capture_group(&cap, "character") {
+            return Ok(Some(LogEvent::CharacterSelected {
+                character: String::from(character),
+                league: cap.name("league").map(|l| String::from(l.as_str())),
+            }));
+        }
+    }
+    if let Some(cap) = config.trade_regex()?.and_then(|r| r.captures(line)) {
+        if let Some(partner) = capture_group(&cap, "partner") {
+            return Ok(Some(LogEvent::TradeCompleted {
+                partner: String::from(partner),
+                items: cap.name("items").map(|i| String::from(i.as_str())),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the text of the named `group` of `cap`.
+///
+/// Groups in alternatives not taking part in the match are missing even
+/// though the regex has them, such matches are skipped.
+fn capture_group<'t>(cap: &Captures<'t>, group: &str) -> Option<&'t str> {
+    let text = cap.name(group).map(|m| m.as_str());
+    if text.is_none() {
+        debug!("skipping match without group {}", group);
+    }
+    text
+}
+
+/// Builds the buy offer matched by `cap` in `line`, if all of its groups
+/// matched.
+fn buy_offer(cap: &Captures<'_>, line: &str) -> Option<BuyOffer> {
+    let stash = match (cap.name("tab"), cap.name("left"), cap.name("top")) {
+        (Some(tab), Some(left), Some(top)) => Some(StashPosition {
+            tab: String::from(tab.as_str()),
+            left: String::from(left.as_str()),
+            top: String::from(top.as_str()),
+        }),
+        _ => None,
+    };
+    Some(BuyOffer {
+        buyer: String::from(capture_group(cap, "buyer")?),
+        object: String::from(capture_group(cap, "object")?),
+        price: String::from(capture_group(cap, "price")?),
+        league: String::from(capture_group(cap, "league")?),
+        location: String::from(cap.name("location").map_or("", |m| m.as_str())),
+        stash,
+        timestamp: parse_timestamp(line),
+    })
+}
+
+/// A line read from a log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    /// Number of the line, starting at 1.
+    pub number: u64,
+    /// Text of the line without its line break, invalid UTF-8 is replaced.
+    pub text: String,
+    /// Bytes the line took in the log, including its line break.
+    pub len: u64,
+}
+
+/// Reads the lines of a log from `reader`, one by one.
+pub fn log_lines<R>(reader: R) -> impl Iterator<Item = Result<LogLine, AtlasError>>
+where
+    R: BufRead,
+{
+    let mut reader = reader;
+    let mut number = 0;
+    iter::from_fn(move || {
+        let mut line = Vec::new();
+        let len = match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(len) => len,
+            Err(e) => return Some(Err(AtlasError::from(e))),
+        };
+        number += 1;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        let text = String::from_utf8_lossy(&line);
+        Some(Ok(LogLine {
+            number,
+            text: String::from(text.trim_end_matches('\r')),
+            len: len as u64,
+        }))
+    })
+}
+
+/// Parses the lines read from `reader` into events, one by one.
+///
+/// Lines not describing an event are skipped. Reading fails on lines that
+/// aren't valid UTF-8.
+///
+/// ```
+/// let mut config = brickatlas::Config::new(
+///     vec![],
+///     vec![String::from("Strand")],
+///     r"You have entered (?P<map>.+)\.",
+///     r"@From (?P<buyer>.+): buy (?P<object>.+) for (?P<price>.+) in (?P<league>.+) \((?P<location>.+)\)",
+/// )?;
+/// let log = "You have entered Strand.\nsomething else\n@From Joe: buy Ring for 5 chaos in Standard (stash)\n";
+/// let events = brickatlas::events(&mut config, log.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+/// assert_eq!(events.len(), 2);
+/// assert_eq!(events[0].to_string(), "entered map Strand");
+/// assert!(matches!(events[1], brickatlas::LogEvent::BuyOffer(_)));
+/// # Ok::<(), brickatlas::AtlasError>(())
+/// ```
+pub fn events<'a, R>(
+    config: &'a mut Config,
+    reader: R,
+) -> impl Iterator<Item = Result<LogEvent, AtlasError>> + 'a
+where
+    R: BufRead + 'a,
+{
+    reader.lines().filter_map(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(AtlasError::from(e))),
+        };
+        parse_line(config, &line).transpose()
+    })
+}
+
+fn handle_line(
+    line: &str,
+    config: &mut Config,
+    session: &mut Session,
+    notifier: &dyn Notifier,
+) -> Result<(), AtlasError> {
+    debug!("processing line: {}", line);
+    let cap = config.mods_regex()?.and_then(|r| r.captures(line));
+    if let Some(mods) = cap.as_ref().and_then(|cap| capture_group(cap, "mods")) {
+        let mods = String::from(mods);
+        debug!("mods_regex matched mods {}", mods);
+        session.remember_mods(mods, config.mods_window_lines());
+        return Ok(());
+    }
+    let event = match parse_line(config, line)? {
+        Some(event) => event,
+        None => return Ok(()),
+    };
+    let timestamp = event.timestamp().or_else(|| parse_timestamp(line));
+    if config.is_too_old(timestamp) {
+        debug!("ignoring event {:?} older than max_event_age_secs", event);
+        return Ok(());
+    }
+    if session.is_repeated(&event, config.dedup_window(), config.clock().now()) {
+        debug!("suppressing repeated event {:?}", event);
+        return Ok(());
+    }
+    session.stats.events_read += 1;
+    notifier.event(config, &event)?;
+    if let LogEvent::CharacterSelected { character, league } = &event {
+        if !session.select_character(character, league.as_deref()) {
+            debug!("still playing character {}", character);
+            return Ok(());
+        }
+    }
+    let snoozed = session.is_snoozed();
+    if snoozed && !matches!(event, LogEvent::MapEntered { .. }) {
+        info!("snoozed, not notifying about {}", event);
+        return Ok(());
+    }
+    if let LogEvent::MapEntered { name, .. } | LogEvent::ZoneEntered { name } = &event {
+        session.in_hideout = name.ends_with(HIDEOUT_SUFFIX);
+    }
+    let in_hideout = config.suppress_in_hideout && session.in_hideout;
+    if in_hideout && matches!(event, LogEvent::BuyOffer(_)) {
+        info!("in hideout, not notifying about {}", event);
+        return Ok(());
+    }
+    match event {
+        LogEvent::MapEntered { name, tier, .. } => {
+            info!("maps_regex matched map {}", name);
+            session.stats.map_matches += 1;
+            let group = config.map_group(&name)?;
+            let entry = config.avoided_map_entry(&name)?;
+            if let Some(entry) = &entry {
+                info!(
+                    "map {} matches the entry {} of the maps to avoid",
+                    name, entry
+                );
+            }
+            let avoided_mod =
+                session.take_avoided_mod(&config.avoid_mods, config.mods_window_lines());
+            if let Some(avoided_mod) = &avoided_mod {
+                info!("map {} has the avoided mod {}", name, avoided_mod);
+            }
+            let avoided = group.is_some()
+                || entry.is_some()
+                || config.is_avoided_tier(tier)
+                || avoided_mod.is_some();
+            session.record_map(config, timestamp, &name, avoided);
+            if avoided != config.invert_maps && snoozed {
+                info!("snoozed, not alerting about map {}", name);
+            } else if avoided != config.invert_maps && in_hideout {
+                info!("in hideout, not alerting about map {}", name);
+            } else if avoided != config.invert_maps {
+                let group = group.map(|i| &config.map_groups[i]);
+                let count = session.stats.map_alerts + 1;
+                let entry = entry.as_deref();
+                if session.notify(config, || notifier.map(config, &name, entry, group, count)) {
+                    session.stats.map_alerts += 1;
+                }
+                session.publish(
+                    config,
+                    &config.map_notifiers,
+                    json!({
+                        "type": "map",
+                        "map": name,
+                        "entry": entry,
+                        "group": group.map(MapGroup::name),
+                        "timestamp": format_timestamp(config.clock(), timestamp),
+                    }),
+                );
+            }
+        }
+        LogEvent::BuyOffer(offer) => {
+            info!("buy_regex matched buyer {}", offer.buyer);
+            session.stats.buyer_matches += 1;
+            session.record_offer(config, timestamp, &offer);
+            if config.is_ignored_buyer(&offer.buyer) {
+                debug!("ignoring offer of ignored buyer {}", offer.buyer);
+                return Ok(());
+            }
+            if config.is_below_min_price(&offer.price) {
+                debug!("ignoring offer of {} below min_price", offer.price);
+                return Ok(());
+            }
+            if config.lacks_rarity(&offer.object) {
+                debug!("ignoring offer for {} without rarity", offer.object);
+                return Ok(());
+            }
+            if session.notify(config, || notifier.buyer(config, &offer, timestamp)) {
+                session.stats.buyer_notifications += 1;
+            }
+            session.publish(
+                config,
+                &config.buyer_notifiers,
+                json!({
+                    "type": "buyer",
+                    "buyer": offer.buyer,
+                    "object": offer.object,
+                    "price": offer.price,
+                    "league": offer.league,
+                    "location": offer.location,
+                    "stash": offer.stash.as_ref().map(|stash| json!({
+                        "tab": stash.tab,
+                        "left": stash.left,
+                        "top": stash.top,
+                    })),
+                    "timestamp": format_timestamp(config.clock(), timestamp),
+                }),
+            );
+        }
+        LogEvent::LevelUp { character, level } => {
+            info!("level_regex matched character {}", character);
+            session.notify(config, || notifier.level(config, &character, &level));
+        }
+        LogEvent::ZoneEntered { name } => {
+            info!("zones_regex matched zone {}", name);
+            session.notify(config, || notifier.zone(config, &name));
+        }
+        LogEvent::AfkChanged { on } => {
+            info!(
+                "afk_regex matched AFK mode {}",
+                if on { "on" } else { "off" }
+            );
+            session.notify(config, || notifier.afk(config, on));
+        }
+        LogEvent::CharacterSelected { character, league } => {
+            info!("character_regex matched character {}", character);
+            session.notify(config, || {
+                notifier.character(config, &character, league.as_deref())
+            });
+        }
+        LogEvent::TradeCompleted { partner, items } => {
+            info!("trade_regex matched partner {}", partner);
+            session.notify(config, || {
+                notifier.trade(config, &partner, items.as_deref())
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Tells how long ago a whisper at `timestamp` arrived, like `3m ago`.
+///
+/// `None` is returned for lines without timestamp.
+fn whisper_age(clock: &dyn Clock, timestamp: Option<NaiveDateTime>) -> Option<String> {
+    let age = (clock.local() - timestamp?).num_seconds().max(0);
+    Some(match age {
+        0..=59 => format!("{}s ago", age),
+        60..=3599 => format!("{}m ago", age / 60),
+        _ => format!("{}h {}m ago", age / 3600, age % 3600 / 60),
+    })
+}
+
+/// Formats the `timestamp` of an event for the history and webhooks.
+///
+/// Events without timestamp are taken as happening now.
+fn format_timestamp(clock: &dyn Clock, timestamp: Option<NaiveDateTime>) -> String {
+    timestamp
+        .unwrap_or_else(|| clock.local())
+        .format(EVENT_TIMESTAMP_FORMAT)
+        .to_string()
+}
+
+/// How long to wait for the webhook to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Posts events to webhooks from a thread of its own, so a slow webhook
+/// doesn't hold up watching.
+///
+/// The events are posted in the order they were sent. Dropping waits for the
+/// events sent so far to be posted.
+#[derive(Debug)]
+struct Webhook {
+    sender: Option<mpsc::Sender<(String, serde_json::Value)>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Webhook {
+    fn start() -> Webhook {
+        let (sender, receiver) = mpsc::channel::<(String, serde_json::Value)>();
+        let thread = thread::spawn(move || {
+            for (url, event) in receiver {
+                post_webhook(&url, &event);
+            }
+        });
+        Webhook {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues `event` to be posted to `url`.
+    fn send(&self, url: &str, event: serde_json::Value) {
+        if let Some(sender) = &self.sender {
+            if sender.send((String::from(url), event)).is_err() {
+                warn!("error while posting to webhook {}: sender stopped", url);
+            }
+        }
+    }
+}
+
+impl Drop for Webhook {
+    fn drop(&mut self) {
+        // closing the channel ends the thread once the queue is empty
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("error while posting to webhook: sender panicked");
+            }
+        }
+    }
+}
+
+/// Posts the `event` to the webhook at `url`.
+///
+/// Failures are only logged to not interrupt watching.
+fn post_webhook(url: &str, event: &serde_json::Value) {
+    let result = ureq::post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(&event.to_string());
+    if let Err(e) = result {
+        warn!("error while posting to webhook {}: {}", url, e);
+    }
+}
+
+/// Splits a price like `50 chaos` into amount and currency.
+fn parse_price(price: &str) -> Option<(f64, &str)> {
+    let mut parts = price.split_whitespace();
+    let amount = parts.next()?.parse().ok()?;
+    let currency = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((amount, currency))
+}
+
+/// Default prefix of whispers coming from other players.
+const DEFAULT_WHISPER_PREFIX: &str = "@From";
+
+/// Checks whether the message in `line` starts with `prefix`.
+///
+/// The message follows the `[INFO Client ...]` block of a log line, lines
+/// without such a block are taken as message.
+fn is_whisper(line: &str, prefix: &str) -> bool {
+    let message = match line.find("] ") {
+        Some(i) => &line[i + 2..],
+        None => line,
+    };
+    message.starts_with(prefix)
+}
+
+/// Default command used to play sounds.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_SOUND_PLAYER: &str = "paplay";
+
+/// Plays `sound` with `player` in the background.
+///
+/// Failures are only printed, as the visual notification is still shown.
+#[cfg(feature = "desktop-notifications")]
+fn play_sound(sound: &str, player: Option<&str>) {
+    let mut args = player.unwrap_or(DEFAULT_SOUND_PLAYER).split_whitespace();
+    let program = match args.next() {
+        Some(program) => program,
+        None => {
+            warn!("error while playing sound: sound_player is empty");
+            return;
+        }
+    };
+    match Command::new(program).args(args).arg(sound).spawn() {
+        // reap the player once it's done so no zombies are left behind
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => warn!("error while playing sound {}: {}", sound, e),
+    }
+}
+
+/// Shows events to the user.
+pub trait Notifier {
+    /// Alerts that the avoided `map` was entered.
+    ///
+    /// `entry` is the entry of the maps to avoid the map matched and `group`
+    /// the map group listing the map, if any. `count` is the number of map
+    /// alerts this session, including this one. Lines naming a map are
+    /// alerted about once, even if the map matches several entries.
+    fn map(
+        &self,
+        config: &Config,
+        map: &str,
+        entry: Option<&str>,
+        group: Option<&MapGroup>,
+        count: u64,
+    ) -> Result<(), AtlasError>;
+
+    /// Notifies about a buy `offer`.
+    ///
+    /// `timestamp` is when the offer was whispered, if the line tells.
+    fn buyer(
+        &self,
+        config: &Config,
+        offer: &BuyOffer,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError>;
+
+    /// Notifies that the configured `zone` was entered.
+    fn zone(&self, config: &Config, zone: &str) -> Result<(), AtlasError>;
+
+    /// Notifies that `character` reached `level`.
+    fn level(&self, config: &Config, character: &str, level: &str) -> Result<(), AtlasError>;
+
+    /// Notifies that AFK mode was turned `on` or off.
+    fn afk(&self, config: &Config, on: bool) -> Result<(), AtlasError>;
+
+    /// Notifies that another `character` was selected, playing in `league`
+    /// if known.
+    fn character(
+        &self,
+        _config: &Config,
+        _character: &str,
+        _league: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    /// Notifies that a trade of `items`, if known, with `partner` was
+    /// completed.
+    fn trade(
+        &self,
+        _config: &Config,
+        _partner: &str,
+        _items: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    /// Notifies that watching `logfiles` started.
+    fn started(&self, _config: &Config, _logfiles: &[String]) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    /// Confirms that notifications were snoozed or, if not `on`, resumed.
+    fn snoozed(&self, _config: &Config, _on: bool) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    /// Tells that watching is still going on, with the `stats` so far.
+    fn heartbeat(&self, _config: &Config, _stats: &RunStats) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    /// Sees every `event` read, whether it is notified about or not.
+    ///
+    /// Only events older than `max_event_age_secs` and repeats within the
+    /// `dedup_window_ms` are left out. Unlike failing notifications, an error
+    /// stops watching and is returned.
+    fn event(&self, _config: &Config, _event: &LogEvent) -> Result<(), AtlasError> {
+        Ok(())
+    }
+}
+
+/// Shows events as desktop notifications.
+#[cfg(feature = "desktop-notifications")]
+#[derive(Debug, Default)]
+pub struct DesktopNotifier {
+    /// Id of the last map notification, replaced by the next one with
+    /// `replace_map_notifications`.
+    last_map_id: Cell<Option<u32>>,
+}
+
+#[cfg(feature = "desktop-notifications")]
+impl Notifier for DesktopNotifier {
+    fn map(
+        &self,
+        config: &Config,
+        map: &str,
+        entry: Option<&str>,
+        group: Option<&MapGroup>,
+        count: u64,
+    ) -> Result<(), AtlasError> {
+        let sound = group
+            .and_then(|g| g.sound.as_deref())
+            .or(config.sound.as_deref());
+        if let Some(sound) = sound {
+            play_sound(sound, config.sound_player.as_deref());
+        }
+        notify_map(config, map, entry, group, count, &self.last_map_id)
+    }
+
+    fn buyer(
+        &self,
+        config: &Config,
+        offer: &BuyOffer,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError> {
+        notify_buyer(config, offer, timestamp)
+    }
+
+    fn zone(&self, config: &Config, zone: &str) -> Result<(), AtlasError> {
+        notify_zone(config, zone)
+    }
+
+    fn level(&self, config: &Config, character: &str, level: &str) -> Result<(), AtlasError> {
+        notify_level(config, character, level)
+    }
+
+    fn afk(&self, config: &Config, on: bool) -> Result<(), AtlasError> {
+        notify_afk(config, on)
+    }
+
+    fn character(
+        &self,
+        config: &Config,
+        character: &str,
+        league: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        notify_character(config, character, league)
+    }
+
+    fn trade(&self, config: &Config, partner: &str, items: Option<&str>) -> Result<(), AtlasError> {
+        notify_trade(config, partner, items)
+    }
+
+    fn started(&self, config: &Config, logfiles: &[String]) -> Result<(), AtlasError> {
+        notify_started(config, logfiles)
+    }
+
+    fn snoozed(&self, config: &Config, on: bool) -> Result<(), AtlasError> {
+        notify_snoozed(config, on, config.snooze_duration())
+    }
+
+    fn heartbeat(&self, config: &Config, stats: &RunStats) -> Result<(), AtlasError> {
+        notify_heartbeat(config, stats)
+    }
+}
+
+/// Opens stdout, colored unless it isn't a terminal or `NO_COLOR` is set.
+fn colored_stdout() -> StandardStream {
+    let choice = if io::stdout().is_terminal() {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    };
+    StandardStream::stdout(choice)
+}
+
+/// Writes `text` to `out` styled with `spec`.
+fn write_styled(out: &mut dyn WriteColor, spec: &ColorSpec, text: &str) -> io::Result<()> {
+    out.set_color(spec)?;
+    write!(out, "{}", text)?;
+    out.reset()
+}
+
+/// Style of the labels telling the kind of event.
+fn colored(color: Color) -> ColorSpec {
+    let mut spec = ColorSpec::new();
+    spec.set_fg(Some(color)).set_bold(true);
+    spec
+}
+
+/// Style of the fields of an event which matter most.
+fn bold() -> ColorSpec {
+    let mut spec = ColorSpec::new();
+    spec.set_bold(true);
+    spec
+}
+
+/// Style of details like when something happened.
+fn dimmed() -> ColorSpec {
+    let mut spec = ColorSpec::new();
+    spec.set_dimmed(true);
+    spec
+}
+
+/// Writes events as lines of text to an output.
+trait TextOutput {
+    /// Output the next event is written to.
+    fn output(&self) -> Box<dyn WriteColor + '_>;
+}
+
+/// Prints events to stdout, for example where no notification daemon runs.
+pub struct StdoutNotifier;
+
+impl TextOutput for StdoutNotifier {
+    fn output(&self) -> Box<dyn WriteColor + '_> {
+        Box::new(colored_stdout())
+    }
+}
+
+/// Appends events to a file as lines of text, like [`StdoutNotifier`]
+/// prints them.
+#[derive(Debug)]
+pub struct FileNotifier {
+    file: File,
+}
+
+impl FileNotifier {
+    /// Opens `path` to append to, creating it if missing.
+    pub fn open(path: &Path) -> Result<FileNotifier, AtlasError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileNotifier { file })
+    }
+}
+
+impl TextOutput for FileNotifier {
+    fn output(&self) -> Box<dyn WriteColor + '_> {
+        Box::new(NoColor::new(&self.file))
+    }
+}
+
+impl<T: TextOutput> Notifier for T {
+    fn map(
+        &self,
+        config: &Config,
+        map: &str,
+        entry: Option<&str>,
+        group: Option<&MapGroup>,
+        _count: u64,
+    ) -> Result<(), AtlasError> {
+        let mut out = self.output();
+        write_styled(&mut out, &colored(Color::Red), "map:")?;
+        if config.invert_maps {
+            write!(out, " unexpected ")?;
+            write_styled(&mut out, &bold(), map)?;
+        } else {
+            write!(out, " do NOT complete ")?;
+            write_styled(&mut out, &bold(), map)?;
+            if let Some(group) = group {
+                write!(out, " ({})", group.name)?;
+            }
+            if let Some(entry) = entry.filter(|entry| *entry != map) {
+                write!(out, " (matches {})", entry)?;
+            }
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn buyer(
+        &self,
+        config: &Config,
+        offer: &BuyOffer,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError> {
+        let mut out = self.output();
+        write_styled(&mut out, &colored(Color::Yellow), "buyer:")?;
+        write!(out, " ")?;
+        write_styled(&mut out, &bold(), &offer.buyer)?;
+        write!(out, " wants ")?;
+        write_styled(&mut out, &bold(), &offer.object)?;
+        write!(out, " for ")?;
+        write_styled(&mut out, &bold(), &config.display_price(&offer.price))?;
+        write!(out, " in {}", offer.league)?;
+        if !offer.location.is_empty() {
+            write!(out, " ({})", offer.location)?;
+        }
+        if let Some(stash) = &offer.stash {
+            write!(out, ", tab {}", stash)?;
+        }
+        if let Some(age) = whisper_age(config.clock(), timestamp) {
+            write!(out, " ")?;
+            write_styled(&mut out, &dimmed(), &format!("({})", age))?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+
+    fn zone(&self, _config: &Config, zone: &str) -> Result<(), AtlasError> {
+        writeln!(self.output(), "zone: entered {}", zone)?;
+        Ok(())
+    }
+
+    fn level(&self, _config: &Config, character: &str, level: &str) -> Result<(), AtlasError> {
+        writeln!(self.output(), "level: {} is now level {}", character, level)?;
+        Ok(())
+    }
+
+    fn afk(&self, _config: &Config, on: bool) -> Result<(), AtlasError> {
+        writeln!(self.output(), "afk: {}", if on { "on" } else { "off" })?;
+        Ok(())
+    }
+
+    fn character(
+        &self,
+        _config: &Config,
+        character: &str,
+        league: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        match league {
+            Some(league) => writeln!(
+                self.output(),
+                "character: playing {} in {}",
+                character,
+                league
+            )?,
+            None => writeln!(self.output(), "character: playing {}", character)?,
+        }
+        Ok(())
+    }
+
+    fn trade(
+        &self,
+        _config: &Config,
+        partner: &str,
+        items: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        match items {
+            Some(items) => writeln!(self.output(), "trade: sold {} to {}", items, partner)?,
+            None => writeln!(self.output(), "trade: completed with {}", partner)?,
+        }
+        Ok(())
+    }
+
+    fn started(&self, _config: &Config, logfiles: &[String]) -> Result<(), AtlasError> {
+        writeln!(self.output(), "started: watching {}", logfiles.join(", "))?;
+        Ok(())
+    }
+
+    fn snoozed(&self, _config: &Config, on: bool) -> Result<(), AtlasError> {
+        writeln!(self.output(), "snooze: {}", if on { "on" } else { "off" })?;
+        Ok(())
+    }
+}
+
+/// Records events instead of showing them, for checking them in tests.
+#[cfg(any(test, feature = "recording"))]
+#[derive(Debug, Default)]
+pub struct RecordingNotifier {
+    events: Mutex<Vec<LogEvent>>,
+}
+
+#[cfg(any(test, feature = "recording"))]
+impl RecordingNotifier {
+    /// Returns the events recorded so far.
+    pub fn events(&self) -> Vec<LogEvent> {
+        self.events
+            .lock()
+            .expect("Error while locking recorded events")
+            .clone()
+    }
+
+    fn record(&self, event: LogEvent) -> Result<(), AtlasError> {
+        self.events
+            .lock()
+            .expect("Error while locking recorded events")
+            .push(event);
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "recording"))]
+impl Notifier for RecordingNotifier {
+    fn map(
+        &self,
+        _config: &Config,
+        map: &str,
+        _entry: Option<&str>,
+        _group: Option<&MapGroup>,
+        _count: u64,
+    ) -> Result<(), AtlasError> {
+        self.record(LogEvent::MapEntered {
+            name: String::from(map),
+            tier: None,
+            timestamp: None,
+        })
+    }
+
+    fn buyer(
+        &self,
+        _config: &Config,
+        offer: &BuyOffer,
+        _timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError> {
+        self.record(LogEvent::BuyOffer(offer.clone()))
+    }
+
+    fn zone(&self, _config: &Config, zone: &str) -> Result<(), AtlasError> {
+        self.record(LogEvent::ZoneEntered {
+            name: String::from(zone),
+        })
+    }
+
+    fn level(&self, _config: &Config, character: &str, level: &str) -> Result<(), AtlasError> {
+        self.record(LogEvent::LevelUp {
+            character: String::from(character),
+            level: String::from(level),
+        })
+    }
+
+    fn afk(&self, _config: &Config, on: bool) -> Result<(), AtlasError> {
+        self.record(LogEvent::AfkChanged { on })
+    }
+
+    fn character(
+        &self,
+        _config: &Config,
+        character: &str,
+        league: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        self.record(LogEvent::CharacterSelected {
+            character: String::from(character),
+            league: league.map(String::from),
+        })
+    }
+
+    fn trade(
+        &self,
+        _config: &Config,
+        partner: &str,
+        items: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        self.record(LogEvent::TradeCompleted {
+            partner: String::from(partner),
+            items: items.map(String::from),
+        })
+    }
+}
+
+/// Passes every event read to a closure instead of showing notifications.
+struct HandlerNotifier<F> {
+    handler: RefCell<F>,
+}
+
+impl<F> Notifier for HandlerNotifier<F>
+where
+    F: FnMut(LogEvent) -> Result<(), AtlasError>,
+{
+    fn map(
+        &self,
+        _config: &Config,
+        _map: &str,
+        _entry: Option<&str>,
+        _group: Option<&MapGroup>,
+        _count: u64,
+    ) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    fn buyer(
+        &self,
+        _config: &Config,
+        _offer: &BuyOffer,
+        _timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    fn zone(&self, _config: &Config, _zone: &str) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    fn level(&self, _config: &Config, _character: &str, _level: &str) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    fn afk(&self, _config: &Config, _on: bool) -> Result<(), AtlasError> {
+        Ok(())
+    }
+
+    fn event(&self, _config: &Config, event: &LogEvent) -> Result<(), AtlasError> {
+        (self.handler.borrow_mut())(event.clone())
+    }
+}
+
+/// Forwards events to several notifiers at once.
+///
+/// Every notifier gets each event, even if an earlier one failed. The first
+/// error is returned, the others are logged.
+#[derive(Default)]
+pub struct CompositeNotifier {
+    notifiers: Vec<Box<dyn Notifier>>,
+}
+
+impl CompositeNotifier {
+    /// Creates a notifier forwarding to all of `notifiers`.
+    pub fn new(notifiers: Vec<Box<dyn Notifier>>) -> CompositeNotifier {
+        CompositeNotifier { notifiers }
+    }
+
+    /// Calls `notify` for every notifier.
+    fn forward<F>(&self, notify: F) -> Result<(), AtlasError>
+    where
+        F: Fn(&dyn Notifier) -> Result<(), AtlasError>,
+    {
+        let mut result = Ok(());
+        for notifier in &self.notifiers {
+            match notify(notifier.as_ref()) {
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(e) => warn!("notifying failed too: {}", e),
+                Ok(()) => {}
+            }
+        }
+        result
+    }
+}
+
+impl Notifier for CompositeNotifier {
+    fn map(
+        &self,
+        config: &Config,
+        map: &str,
+        entry: Option<&str>,
+        group: Option<&MapGroup>,
+        count: u64,
+    ) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.map(config, map, entry, group, count))
+    }
+
+    fn buyer(
+        &self,
+        config: &Config,
+        offer: &BuyOffer,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.buyer(config, offer, timestamp))
+    }
+
+    fn zone(&self, config: &Config, zone: &str) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.zone(config, zone))
+    }
+
+    fn level(&self, config: &Config, character: &str, level: &str) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.level(config, character, level))
+    }
+
+    fn afk(&self, config: &Config, on: bool) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.afk(config, on))
+    }
+
+    fn character(
+        &self,
+        config: &Config,
+        character: &str,
+        league: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.character(config, character, league))
+    }
+
+    fn trade(&self, config: &Config, partner: &str, items: Option<&str>) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.trade(config, partner, items))
+    }
+
+    fn started(&self, config: &Config, logfiles: &[String]) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.started(config, logfiles))
+    }
+
+    fn snoozed(&self, config: &Config, on: bool) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.snoozed(config, on))
+    }
+
+    fn heartbeat(&self, config: &Config, stats: &RunStats) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.heartbeat(config, stats))
+    }
+
+    fn event(&self, config: &Config, event: &LogEvent) -> Result<(), AtlasError> {
+        self.forward(|notifier| notifier.event(config, event))
+    }
+}
+
+/// Shows map alerts, buy offers and all other events through notifiers of
+/// their own.
+struct RoutingNotifier {
+    map: CompositeNotifier,
+    buyer: CompositeNotifier,
+    other: CompositeNotifier,
+}
+
+impl Notifier for RoutingNotifier {
+    fn map(
+        &self,
+        config: &Config,
+        map: &str,
+        entry: Option<&str>,
+        group: Option<&MapGroup>,
+        count: u64,
+    ) -> Result<(), AtlasError> {
+        self.map.map(config, map, entry, group, count)
+    }
+
+    fn buyer(
+        &self,
+        config: &Config,
+        offer: &BuyOffer,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), AtlasError> {
+        self.buyer.buyer(config, offer, timestamp)
+    }
+
+    fn zone(&self, config: &Config, zone: &str) -> Result<(), AtlasError> {
+        self.other.zone(config, zone)
+    }
+
+    fn level(&self, config: &Config, character: &str, level: &str) -> Result<(), AtlasError> {
+        self.other.level(config, character, level)
+    }
+
+    fn afk(&self, config: &Config, on: bool) -> Result<(), AtlasError> {
+        self.other.afk(config, on)
+    }
+
+    fn character(
+        &self,
+        config: &Config,
+        character: &str,
+        league: Option<&str>,
+    ) -> Result<(), AtlasError> {
+        self.other.character(config, character, league)
+    }
+
+    fn trade(&self, config: &Config, partner: &str, items: Option<&str>) -> Result<(), AtlasError> {
+        self.other.trade(config, partner, items)
+    }
+
+    fn started(&self, config: &Config, logfiles: &[String]) -> Result<(), AtlasError> {
+        self.other.started(config, logfiles)
+    }
+
+    fn snoozed(&self, config: &Config, on: bool) -> Result<(), AtlasError> {
+        self.other.snoozed(config, on)
+    }
+
+    fn heartbeat(&self, config: &Config, stats: &RunStats) -> Result<(), AtlasError> {
+        self.other.heartbeat(config, stats)
+    }
+
+    fn event(&self, config: &Config, event: &LogEvent) -> Result<(), AtlasError> {
+        match event {
+            LogEvent::MapEntered { .. } => self.map.event(config, event),
+            LogEvent::BuyOffer(_) => self.buyer.event(config, event),
+            _ => self.other.event(config, event),
+        }
+    }
+}
+
+/// Default milliseconds a notification is shown.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_TIMEOUT_MS: u32 = 5000;
+
+/// Returns the configured `timeout` or the default one.
+#[cfg(feature = "desktop-notifications")]
+fn timeout(timeout: Option<Timeout>) -> Timeout {
+    timeout.unwrap_or(Timeout::Milliseconds(DEFAULT_TIMEOUT_MS))
+}
+
+/// Default summary of the map notification.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_MAP_SUMMARY: &str = "brickatlas map";
+/// Default body of the map notification.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_MAP_BODY: &str = "Do <u><b>NOT</b></u> complete: {map}";
+/// Default body of the map notification with `invert_maps`.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_INVERTED_MAP_BODY: &str = "<b>Unexpected</b> map: {map}";
+
+#[cfg(feature = "desktop-notifications")]
+lazy_static! {
+    /// Markup tags of notification bodies, like `<b>` or `</u>`.
+    static ref MARKUP_TAG_REGEX: Regex = Regex::new(r"</?[a-zA-Z]+>").expect("invalid MARKUP_TAG_REGEX");
+    /// Whether notification bodies are rendered with their markup, asked
+    /// once.
+    static ref BODY_MARKUP_SUPPORTED: bool = body_markup_supported();
+}
+
+/// Asks the notification daemon whether it renders markup in bodies.
+///
+/// Daemons which can't be asked are expected to render it, as most do.
+#[cfg(all(feature = "desktop-notifications", unix, not(target_os = "macos")))]
+fn body_markup_supported() -> bool {
+    match notify_rust::get_capabilities() {
+        Ok(capabilities) => {
+            let supported = capabilities.iter().any(|c| c == "body-markup");
+            if !supported {
+                info!("notification daemon doesn't render markup, removing it");
+            }
+            supported
+        }
+        Err(e) => {
+            debug!("error while asking for notification capabilities: {}", e);
+            true
+        }
+    }
+}
+
+/// Windows and macOS show markup tags literally.
+#[cfg(all(
+    feature = "desktop-notifications",
+    not(all(unix, not(target_os = "macos")))
+))]
+fn body_markup_supported() -> bool {
+    false
+}
+
+/// Prepares the notification `body` for the notification daemon.
+#[cfg(feature = "desktop-notifications")]
+fn body_markup(body: &str) -> Cow<'_, str> {
+    render_body(*BODY_MARKUP_SUPPORTED, body)
+}
+
+/// Keeps the markup of `template` if `markup_supported`, otherwise removes
+/// its tags so they aren't shown literally.
+#[cfg(feature = "desktop-notifications")]
+fn render_body(markup_supported: bool, template: &str) -> Cow<'_, str> {
+    if markup_supported {
+        Cow::Borrowed(template)
+    } else {
+        MARKUP_TAG_REGEX.replace_all(template, "")
+    }
+}
+
+/// Fills the `{map}` placeholder of `template`.
+#[cfg(feature = "desktop-notifications")]
+fn render_map_template(template: &str, map: &str) -> String {
+    template.replace("{map}", map)
+}
+
+/// Shows `icon` with `notification`.
+///
+/// Missing icon files are skipped, so the notification is still shown.
+#[cfg(feature = "desktop-notifications")]
+fn set_icon(notification: &mut Notification, icon: Option<&str>) {
+    if let Some(icon) = icon {
+        if Path::new(icon).is_file() {
+            notification.icon(icon);
+        } else {
+            warn!(
+                "icon {} doesn't exist, showing notification without it",
+                icon
+            );
+        }
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_map(
+    config: &Config,
+    map: &str,
+    entry: Option<&str>,
+    group: Option<&MapGroup>,
+    count: u64,
+    last_id: &Cell<Option<u32>>,
+) -> Result<(), AtlasError> {
+    let summary = group
+        .and_then(|g| g.notification_summary.as_deref())
+        .or(config.map_notification_summary.as_deref())
+        .unwrap_or(DEFAULT_MAP_SUMMARY);
+    let mut summary = render_map_template(summary, map);
+    if config.map_alert_counter {
+        summary.push_str(&format!(" (#{})", count));
+    }
+    let body = group
+        .and_then(|g| g.notification_body.as_deref())
+        .or(config.map_notification_body.as_deref())
+        .unwrap_or(if config.invert_maps {
+            DEFAULT_INVERTED_MAP_BODY
+        } else {
+            DEFAULT_MAP_BODY
+        });
+    let mut body = render_map_template(body, map);
+    if let Some(entry) = entry.filter(|entry| *entry != map) {
+        body.push_str(&format!(" (matches {})", entry));
+    }
+    let mut notification = Notification::new();
+    notification
+        .summary(&summary)
+        .body(&body_markup(&body))
+        .timeout(timeout(
+            group.and_then(|g| g.timeout_ms).or(config.map_timeout_ms),
+        ))
+        .urgency(
+            group
+                .and_then(|g| g.urgency)
+                .or(config.map_urgency)
+                .unwrap_or(Urgency::Critical),
+        );
+    set_icon(&mut notification, config.map_icon.as_deref());
+    if config.replace_map_notifications {
+        if let Some(id) = last_id.get() {
+            notification.id(id);
+        }
+    }
+    show_replacing(config, notification, last_id)
+}
+
+/// Shows `notification`, remembering its id in `last_id` so it can be
+/// replaced.
+#[cfg(feature = "desktop-notifications")]
+#[cfg(all(unix, not(target_os = "macos")))]
+fn show_replacing(
+    config: &Config,
+    notification: Notification,
+    last_id: &Cell<Option<u32>>,
+) -> Result<(), AtlasError> {
+    let handle = show_retrying(config, || notification.show())?;
+    last_id.set(Some(handle.id()));
+    Ok(())
+}
+
+/// Shows `notification`, replacing isn't supported on this platform.
+#[cfg(feature = "desktop-notifications")]
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn show_replacing(
+    config: &Config,
+    notification: Notification,
+    _last_id: &Cell<Option<u32>>,
+) -> Result<(), AtlasError> {
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+/// Body of the buyer notification for `offer`, whispered at `timestamp`.
+#[cfg(feature = "desktop-notifications")]
+fn buyer_body(config: &Config, offer: &BuyOffer, timestamp: Option<NaiveDateTime>) -> String {
+    let mut body = format!(
+        r"buyer: <b>{}</b>
+object: <b>{}</b>
+price: <b>{}</b>
+league: <b>{}</b>",
+        offer.buyer,
+        offer.object,
+        config.display_price(&offer.price),
+        offer.league,
+    );
+    if !offer.location.is_empty() {
+        body.push_str(&format!("\nlocation: <b>{}</b>", offer.location));
+    }
+    if let Some(stash) = &offer.stash {
+        body.push_str(&format!(
+            "\ntab: <b>{}</b> (left <b>{}</b>, top <b>{}</b>)",
+            stash.tab, stash.left, stash.top
+        ));
+    }
+    if let Some(age) = whisper_age(config.clock(), timestamp) {
+        body.push_str(&format!(" ({})", age));
+    }
+    body
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_buyer(
+    config: &Config,
+    offer: &BuyOffer,
+    timestamp: Option<NaiveDateTime>,
+) -> Result<(), AtlasError> {
+    let body = buyer_body(config, offer, timestamp);
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas buyer")
+        .body(&body_markup(&body))
+        .timeout(timeout(config.buyer_timeout_ms))
+        .urgency(config.buyer_urgency.unwrap_or(Urgency::Normal));
+    set_icon(&mut notification, config.buyer_icon.as_deref());
+    show_with_invite(config, notification, &offer.buyer)
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_zone(config: &Config, zone: &str) -> Result<(), AtlasError> {
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas zone")
+        .body(&body_markup(&format!("Entered <b>{}</b>", zone)))
+        .timeout(timeout(None));
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_level(config: &Config, character: &str, level: &str) -> Result<(), AtlasError> {
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas level")
+        .body(&body_markup(&format!(
+            "<b>{}</b> is now level <b>{}</b>",
+            character, level
+        )))
+        .timeout(timeout(None));
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_afk(config: &Config, on: bool) -> Result<(), AtlasError> {
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas afk")
+        .body(&body_markup(if on {
+            "AFK mode is <b>ON</b>"
+        } else {
+            "AFK mode is <b>OFF</b>"
+        }))
+        .timeout(timeout(None))
+        .urgency(Urgency::Low);
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_character(
+    config: &Config,
+    character: &str,
+    league: Option<&str>,
+) -> Result<(), AtlasError> {
+    let body = match league {
+        Some(league) => format!("Playing <b>{}</b> in <b>{}</b>", character, league),
+        None => format!("Playing <b>{}</b>", character),
+    };
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas character")
+        .body(&body_markup(&body))
+        .timeout(timeout(None))
+        .urgency(Urgency::Low);
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_trade(config: &Config, partner: &str, items: Option<&str>) -> Result<(), AtlasError> {
+    let body = match items {
+        Some(items) => format!("Sold <b>{}</b> to <b>{}</b>", items, partner),
+        None => format!("Traded with <b>{}</b>", partner),
+    };
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas trade")
+        .body(&body_markup(&body))
+        .timeout(timeout(None))
+        .urgency(Urgency::Normal);
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_started(config: &Config, logfiles: &[String]) -> Result<(), AtlasError> {
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas started")
+        .body(format!("brickatlas watching {}", logfiles.join(", ")).as_str())
+        .timeout(timeout(None))
+        .urgency(Urgency::Low);
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_snoozed(config: &Config, on: bool, duration: Duration) -> Result<(), AtlasError> {
+    let body = if on {
+        format!(
+            "Notifications snoozed for <b>{}</b> minutes",
+            duration.as_secs() / 60
+        )
+    } else {
+        String::from("Notifications resumed")
+    };
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas snooze")
+        .body(&body_markup(&body))
+        .timeout(timeout(None))
+        .urgency(Urgency::Low);
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn notify_heartbeat(config: &Config, stats: &RunStats) -> Result<(), AtlasError> {
+    let mut notification = Notification::new();
+    notification
+        .summary("brickatlas heartbeat")
+        .body(&body_markup(&format!(
+            "Still watching, read <b>{}</b> events so far",
+            stats.events_read
+        )))
+        .timeout(timeout(None))
+        .urgency(Urgency::Low);
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+/// Default number of times showing a notification is retried.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_NOTIFICATION_RETRIES: u32 = 3;
+/// Default milliseconds to wait before the first retry, doubled for each
+/// further one.
+#[cfg(feature = "desktop-notifications")]
+const DEFAULT_NOTIFICATION_BACKOFF_MS: u64 = 200;
+/// Longest time to wait before retrying a notification, however often it
+/// was doubled.
+#[cfg(feature = "desktop-notifications")]
+const MAX_NOTIFICATION_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Calls `show` until the notification could be shown or the configured
+/// retries are used up.
+///
+/// Retrying helps with a notification daemon which isn't available yet, as
+/// right after login.
+#[cfg(feature = "desktop-notifications")]
+fn show_retrying<T>(
+    config: &Config,
+    show: impl Fn() -> Result<T, notify_rust::error::Error>,
+) -> Result<T, AtlasError> {
+    let retries = config
+        .notification_retries
+        .unwrap_or(DEFAULT_NOTIFICATION_RETRIES);
+    let mut backoff = Duration::from_millis(
+        config
+            .notification_backoff_ms
+            .unwrap_or(DEFAULT_NOTIFICATION_BACKOFF_MS),
+    )
+    .min(MAX_NOTIFICATION_BACKOFF);
+    let mut attempt = 0;
+    loop {
+        match show() {
+            Ok(shown) => return Ok(shown),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                debug!(
+                    "error while showing notification, retry {} of {}: {}",
+                    attempt, retries, e
+                );
+                config.clock().sleep(backoff);
+                backoff = backoff.saturating_mul(2).min(MAX_NOTIFICATION_BACKOFF);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Action of the buyer notification copying the invite command.
+#[cfg(feature = "desktop-notifications")]
+#[cfg(all(unix, not(target_os = "macos")))]
+const COPY_INVITE_ACTION: &str = "copy_invite";
+
+/// Shows `notification` with an action copying the invite for `buyer`.
+///
+/// Clicks on the action are handled in the background.
+#[cfg(feature = "desktop-notifications")]
+#[cfg(all(unix, not(target_os = "macos")))]
+fn show_with_invite(
+    config: &Config,
+    mut notification: Notification,
+    buyer: &str,
+) -> Result<(), AtlasError> {
+    notification.action(COPY_INVITE_ACTION, "Copy invite");
+    let handle = show_retrying(config, || notification.show())?;
+    let invite = format!("/invite {}", buyer);
+    thread::spawn(move || {
+        handle.wait_for_action(|action| {
+            if action == COPY_INVITE_ACTION {
+                copy_to_clipboard(invite);
+            }
+        })
+    });
+    Ok(())
+}
+
+/// Shows `notification`, actions aren't supported on this platform.
+#[cfg(feature = "desktop-notifications")]
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn show_with_invite(
+    config: &Config,
+    notification: Notification,
+    _buyer: &str,
+) -> Result<(), AtlasError> {
+    show_retrying(config, || notification.show())?;
+    Ok(())
+}
+
+/// Puts `text` onto the clipboard.
+///
+/// Blocks until another application takes over the clipboard, as the
+/// contents are gone on X11 once we stop serving them.
+#[cfg(feature = "desktop-notifications")]
+#[cfg(all(unix, not(target_os = "macos")))]
+fn copy_to_clipboard(text: String) {
+    use arboard::SetExtLinux;
+
+    let result = arboard::Clipboard::new().and_then(|mut c| c.set().wait().text(text));
+    if let Err(e) = result {
+        warn!("error while copying to clipboard: {}", e);
+    }
+}
+
+/// Toggles `snoozed` whenever `command` is entered on stdin.
+///
+/// The lines are read in the background until stdin is closed.
+fn listen_for_snooze(command: String, snoozed: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) if line.trim() == command => {
+                    snoozed.fetch_xor(true, Ordering::SeqCst);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("error while reading stdin: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Polls every second until `file` exists or `timeout` seconds passed.
+///
+/// Waiting ends early once `running` is unset.
+fn wait_for_file(
+    file: &str,
+    timeout: Option<u64>,
+    clock: &dyn Clock,
+    running: &AtomicBool,
+) -> Result<(), AtlasError> {
+    let start = clock.now();
+    while !Path::new(file).exists() && running.load(Ordering::SeqCst) {
+        if let Some(timeout) = timeout {
+            if clock.now().saturating_duration_since(start) >= Duration::from_secs(timeout) {
+                return Err(AtlasError::ConfigError(format!(
+                    "watchfile ({}) didn't appear within {}s",
+                    file, timeout
+                )));
+            }
+        }
+        clock.sleep(Duration::from_secs(1));
+    }
+    Ok(())
+}
+
+/// Opens the archived log `file` for reading all of its lines.
+///
+/// Files ending in `.gz` are decompressed while reading.
+fn open_archived(file: &Path) -> Result<Box<dyn BufRead>, AtlasError> {
+    let reader = File::open(file)?;
+    if is_gzipped(file) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(BufReader::new(reader)))
+    }
+}
+
+/// Checks whether `file` is gzip compressed, judging by its extension.
+fn is_gzipped(file: &Path) -> bool {
+    file.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// Prints the events found in `file` with their line numbers.
+///
+/// Files ending in `.gz` are decompressed while reading.
+pub fn test_file(config: &mut Config, file: &str) -> Result<(), AtlasError> {
+    let reader = open_archived(Path::new(file))?;
+    let mut out = colored_stdout();
+    for line in log_lines(reader) {
+        let line = line?;
+        let event = match parse_line(config, &line.text)? {
+            Some(event) => event,
+            None => continue,
+        };
+        write_styled(&mut out, &dimmed(), &format!("{}:", line.number))?;
+        write!(out, " ")?;
+        let spec = match event {
+            LogEvent::MapEntered { .. } => colored(Color::Red),
+            LogEvent::BuyOffer(_) => colored(Color::Yellow),
+            _ => ColorSpec::new(),
+        };
+        write_styled(&mut out, &spec, &event.to_string())?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Handles the lines already in the log files without watching them.
+///
+/// The files are read from the position saved in `state_file`, or from their
+/// start. Files ending in `.gz` are decompressed and always read completely.
+fn read_once(config: &mut Config, notifier: &dyn Notifier) -> Result<RunStats, AtlasError> {
+    let saved_positions = match &config.state_file {
+        Some(file) => load_positions(file)?,
+        None => HashMap::new(),
+    };
+    let mut files = HashMap::new();
+    let mut session = Session::new(config)?;
+    for logfile in config.logfiles.clone() {
+        let path = absolute_path(&logfile)?;
+        if is_gzipped(&path) {
+            read_archived(&path, config, &mut session, notifier)?;
+            continue;
+        }
+        let mut file = LogFile::reopen(&path)?;
+        if let Some(&position) = saved_positions.get(&path) {
+            file.restore_position(position)?;
+        }
+        read_new_lines(&path, &mut file, config, &mut session, notifier)?;
+        files.insert(path, file);
+    }
+    if let Some(file) = &config.state_file {
+        save_positions(file, &files)?;
+    }
+
+    info!("done, {}", session.stats);
+    Ok(session.stats)
+}
+
+/// Handles all lines of the archived log file at `path`.
+fn read_archived(
+    path: &Path,
+    config: &mut Config,
+    session: &mut Session,
+    notifier: &dyn Notifier,
+) -> Result<(), AtlasError> {
+    for line in log_lines(open_archived(path)?) {
+        let line = line?;
+        session.stats.lines_read += 1;
+        session.stats.bytes_read += line.len;
+        handle_line(&line.text, config, session, notifier)?;
+    }
+    Ok(())
+}
+
+/// How often the event loop checks whether it should shut down.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Runs the application given a certain configuration.
+///
+/// Events are shown as desktop notifications, or printed with `dry_run`.
+/// If a file to test was given, the events found in it are printed instead
+/// of watching. Watching stops on Ctrl-C, or after reading with `once`, then
+/// a short summary is printed.
+pub fn run(config: &mut Config) -> Result<(), AtlasError> {
+    if let Some(file) = config.test_file.clone() {
+        return test_file(config, &file);
+    }
+    if config.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = Arc::clone(&running);
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst))?;
+    }
+    let stats = run_with_stats(config, &running)?;
+    println!("brickatlas {}", stats);
     Ok(())
 }
+
+/// Runs the application like [`run`] until `running` is unset, returning
+/// what happened once watching stopped.
+///
+/// No signal handler is installed, stopping is left to the caller.
+pub fn run_with_stats(config: &mut Config, running: &AtomicBool) -> Result<RunStats, AtlasError> {
+    if config.dry_run {
+        run_with_notifier(config, &StdoutNotifier, running)
+    } else {
+        let notifier = config.notifier()?;
+        run_with_notifier(config, &notifier, running)
+    }
+}
+
+/// Runs the application passing the events to `handler` instead of showing
+/// them, until `running` is unset.
+///
+/// `handler` gets every event read, whether it would be notified about or
+/// not, as described at [`Notifier::event`]. Returning an error stops
+/// watching, the error is returned then. No signal handler is installed,
+/// stopping is left to the caller.
+pub fn run_with_handler<F>(
+    config: &mut Config,
+    running: &AtomicBool,
+    handler: F,
+) -> Result<RunStats, AtlasError>
+where
+    F: FnMut(LogEvent) -> Result<(), AtlasError>,
+{
+    let notifier = HandlerNotifier {
+        handler: RefCell::new(handler),
+    };
+    run_with_notifier(config, &notifier, running)
+}
+
+/// Runs the application showing the events through `notifier` until
+/// `running` is unset.
+///
+/// No signal handler is installed, so this can be stopped from another thread,
+/// for example in tests. Failing notifications are only counted.
+pub fn run_with_notifier(
+    config: &mut Config,
+    notifier: &dyn Notifier,
+    running: &AtomicBool,
+) -> Result<RunStats, AtlasError> {
+    if config.logfiles.is_empty() {
+        return Err(AtlasError::ConfigError(String::from(
+            "no watchfile configured",
+        )));
+    }
+    for logfile in &config.logfiles {
+        if config.wait_for_logfile {
+            wait_for_file(
+                logfile,
+                config.wait_for_logfile_timeout,
+                config.clock(),
+                running,
+            )?;
+        } else if !Path::new(logfile).exists() {
+            return Err(AtlasError::ConfigError(format!(
+                "watchfile ({}) doesn't exist",
+                logfile
+            )));
+        }
+    }
+    if !running.load(Ordering::SeqCst) {
+        return Ok(RunStats::default());
+    }
+    if config.once {
+        return read_once(config, notifier);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher: Box<dyn Watcher> = match config.watch_mode {
+        WatchMode::Native => Box::new(RecommendedWatcher::new(tx, notify::Config::default())?),
+        WatchMode::Poll => {
+            info!("polling the log files every {:?}", config.poll_interval());
+            Box::new(PollWatcher::new(
+                tx,
+                notify::Config::default().with_poll_interval(config.poll_interval()),
+            )?)
+        }
+    };
+    let saved_positions = match &config.state_file {
+        Some(file) => load_positions(file)?,
+        None => HashMap::new(),
+    };
+    let mut files = HashMap::new();
+    // followed symlinks with the files they point to
+    let mut links = HashMap::new();
+    for logfile in &config.logfiles {
+        let mut path = absolute_path(logfile)?;
+        // watch the directory to notice the file being replaced
+        let directory = path.parent().unwrap_or(&path);
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        if config.follow_symlinks {
+            let target = fs::canonicalize(&path)?;
+            if target != path {
+                info!("{} points to {}, watching it", logfile, target.display());
+                watcher.watch(
+                    target.parent().unwrap_or(&target),
+                    RecursiveMode::NonRecursive,
+                )?;
+                links.insert(path, target.clone());
+                path = target;
+            }
+        }
+
+        files.insert(path, LogFile::open(logfile)?);
+    }
+
+    let mut session = Session::new(config)?;
+    for (path, file) in files.iter_mut() {
+        if let Some(&position) = saved_positions.get(path) {
+            file.restore_position(position)?;
+            info!(
+                "continuing {} from position {}",
+                path.display(),
+                file.position
+            );
+        } else if config.backlog_lines > 0 {
+            file.rewind_lines(config.backlog_lines)?;
+        } else {
+            continue;
+        }
+        read_new_lines(path, file, config, &mut session, notifier)?;
+    }
+    if let Some(file) = &config.config_file {
+        // watch the directory to notice the file being replaced on save
+        watcher.watch(file.parent().unwrap_or(file), RecursiveMode::NonRecursive)?;
+    }
+
+    if config.startup_notification {
+        let result = notifier.started(config, &config.logfiles);
+        session.notified(result);
+    }
+    if let Some(command) = &config.snooze_command {
+        info!("enter {} to snooze notifications", command);
+        listen_for_snooze(command.clone(), Arc::clone(&session.snoozed));
+    }
+
+    // events arriving while debouncing, handled together once it's over
+    let mut pending = Vec::new();
+    let mut debounce_until: Option<Instant> = None;
+    while running.load(Ordering::SeqCst) {
+        let timeout = debounce_until.map_or(SHUTDOWN_CHECK_INTERVAL, |until| {
+            until
+                .saturating_duration_since(config.clock().now())
+                .min(SHUTDOWN_CHECK_INTERVAL)
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(res) => {
+                let event = res?;
+                if debounce_until.is_some() {
+                    pending.push(event);
+                } else {
+                    handle_event(
+                        event,
+                        config,
+                        &mut files,
+                        &mut links,
+                        watcher.as_mut(),
+                        &mut session,
+                        notifier,
+                    )?;
+                    debounce_until = Some(config.clock().now() + config.debounce());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        session.update_snooze(config, notifier);
+        session.heartbeat(config, notifier);
+        if debounce_until.is_some_and(|until| config.clock().now() >= until) {
+            debounce_until = None;
+            if !pending.is_empty() {
+                for event in pending.drain(..) {
+                    handle_event(
+                        event,
+                        config,
+                        &mut files,
+                        &mut links,
+                        watcher.as_mut(),
+                        &mut session,
+                        notifier,
+                    )?;
+                }
+                debounce_until = Some(config.clock().now() + config.debounce());
+            }
+        }
+    }
+    drop(watcher);
+    if let Some(file) = &config.state_file {
+        save_positions(file, &files)?;
+    }
+    drop(files);
+
+    info!("stopped watching, {}", session.stats);
+    Ok(session.stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const MAPS_REGEX: &str = r"You have entered (?P<map>.+)\.";
+    const BUY_REGEX: &str = r"@From (?P<buyer>.+): buy (?P<object>.+) for (?P<price>.+) in (?P<league>.+) \((?P<location>.+)\)";
+
+    /// Configuration avoiding `maps`, with the default regexes.
+    fn test_config(maps: &[&str]) -> Config {
+        Config::new(
+            vec![],
+            maps.iter().map(|map| String::from(*map)).collect(),
+            MAPS_REGEX,
+            BUY_REGEX,
+        )
+        .unwrap()
+    }
+
+    /// Handles `lines` in one session, returning the notified events.
+    fn notified(config: &mut Config, lines: &[&str]) -> Vec<LogEvent> {
+        let notifier = RecordingNotifier::default();
+        let mut session = Session::new(config).unwrap();
+        for line in lines {
+            session.stats.lines_read += 1;
+            handle_line(line, config, &mut session, &notifier).unwrap();
+        }
+        notifier.events()
+    }
+
+    /// Accepts a single HTTP request, returning its body.
+    fn receive_request(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut len = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header == "\r\n" {
+                break;
+            }
+            if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                len = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn webhook_receives_map_alert() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || receive_request(listener));
+        let mut config = test_config(&["Strand"]).with_webhook_url(&url);
+        notified(
+            &mut config,
+            &["2024/01/01 10:00:00 1 a [INFO] : You have entered Strand."],
+        );
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["type"], "map");
+        assert_eq!(body["map"], "Strand");
+        assert_eq!(body["timestamp"], "2024-01-01T10:00:00");
+    }
+
+    #[test]
+    fn invalid_maps_regex_is_an_error() {
+        let toml = format!("maps_regex = \"(\"\nbuy_regex = '{}'\n", BUY_REGEX);
+        let result = Config::new_from_reader(toml.as_bytes());
+        assert!(matches!(result, Err(AtlasError::RegexError(_))));
+    }
+
+    #[test]
+    fn invalid_maps_regex_fails_compiling() {
+        let mut config = Config {
+            maps_regex: String::from("("),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.compiled_maps_regex(),
+            Err(AtlasError::RegexError(_))
+        ));
+    }
+
+    /// Map line entering `map`.
+    fn map_line(map: &str) -> String {
+        format!("2024/01/01 10:00:00 1 a [INFO] : You have entered {}.", map)
+    }
+
+    /// Names of the maps notified about for `lines`.
+    fn notified_maps(config: &mut Config, lines: &[String]) -> Vec<String> {
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        notified(config, &lines)
+            .into_iter()
+            .filter_map(|event| match event {
+                LogEvent::MapEntered { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn case_insensitive_matches_mixed_casing() {
+        let lines = [
+            map_line("strand map"),
+            map_line("STRAND MAP"),
+            map_line("Dunes"),
+        ];
+        let mut config = test_config(&["Strand Map"]).with_case_insensitive(true);
+        assert_eq!(
+            notified_maps(&mut config, &lines),
+            ["strand map", "STRAND MAP"]
+        );
+        let mut config = test_config(&["Strand Map"]);
+        assert!(notified_maps(&mut config, &lines).is_empty());
+    }
+
+    /// Log file in the temp directory, removed again when dropped.
+    struct TempLog {
+        path: PathBuf,
+    }
+
+    impl TempLog {
+        /// Creates the empty log `name`, unique to this test run.
+        fn new(name: &str) -> TempLog {
+            let path =
+                std::env::temp_dir().join(format!("brickatlas-{}-{}", std::process::id(), name));
+            File::create(&path).unwrap();
+            TempLog { path }
+        }
+
+        fn append(&self, lines: &[String]) {
+            let mut file = OpenOptions::new().append(true).open(&self.path).unwrap();
+            for line in lines {
+                writeln!(file, "{}", line).unwrap();
+            }
+        }
+
+        fn truncate(&self) {
+            File::create(&self.path).unwrap();
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /// Reads a log like watching does, keeping the session between reads.
+    struct Watch {
+        file: LogFile,
+        session: Session,
+        notifier: RecordingNotifier,
+    }
+
+    impl Watch {
+        /// Starts reading `log` at its current end.
+        fn new(config: &Config, log: &TempLog) -> Watch {
+            Watch {
+                file: LogFile::open(log.path.to_str().unwrap()).unwrap(),
+                session: Session::new(config).unwrap(),
+                notifier: RecordingNotifier::default(),
+            }
+        }
+
+        /// Reads the new lines of `log`, returning all maps notified so far.
+        fn read(&mut self, config: &mut Config, log: &TempLog) -> Vec<String> {
+            read_new_lines(
+                &log.path,
+                &mut self.file,
+                config,
+                &mut self.session,
+                &self.notifier,
+            )
+            .unwrap();
+            self.notifier
+                .events()
+                .into_iter()
+                .filter_map(|event| match event {
+                    LogEvent::MapEntered { name, .. } => Some(name),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn truncated_log_is_read_from_its_start() {
+        let mut config = test_config(&["Strand", "Dunes", "Core"]);
+        let log = TempLog::new("truncated.txt");
+        let mut watch = Watch::new(&config, &log);
+        log.append(&[map_line("Strand"), map_line("Dunes")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand", "Dunes"]);
+        log.truncate();
+        log.append(&[map_line("Core")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand", "Dunes", "Core"]);
+    }
+
+    #[test]
+    fn only_incoming_whispers_are_offers() {
+        let mut config = Config::new(
+            vec![],
+            vec![],
+            MAPS_REGEX,
+            r"@(?:From|To) (?P<buyer>.+): buy (?P<object>.+) for (?P<price>.+) in (?P<league>.+) \((?P<location>.+)\)",
+        )
+        .unwrap();
+        let events = notified(
+            &mut config,
+            &[
+                "2024/01/01 10:00:00 1 a [INFO] @To Seller: buy Ring for 5 chaos in Standard (stash)",
+                "2024/01/01 10:00:01 1 a [INFO] @From Buyer: buy Amulet for 3 chaos in Standard (stash)",
+            ],
+        );
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], LogEvent::BuyOffer(offer) if offer.buyer == "Buyer"));
+    }
+
+    #[test]
+    fn invalid_utf8_line_does_not_stop_reading() {
+        let mut config = test_config(&["Strand"]);
+        let log = TempLog::new("invalid-utf8.txt");
+        let mut watch = Watch::new(&config, &log);
+        let mut file = OpenOptions::new().append(true).open(&log.path).unwrap();
+        file.write_all(b"2024/01/01 10:00:00 1 a [INFO] : caf\xe9\n")
+            .unwrap();
+        log.append(&[map_line("Strand")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand"]);
+    }
+
+    #[test]
+    fn exact_and_contains_match_modes() {
+        let lines = [
+            map_line("Strand"),
+            map_line("Strand Map"),
+            map_line("Dunes"),
+        ];
+        let mut config = test_config(&["Strand"]);
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand"]);
+        config.match_mode = MatchMode::Contains;
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand", "Strand Map"]);
+    }
+
+    #[test]
+    fn invert_maps_alerts_on_unlisted_maps() {
+        let lines = [map_line("Strand"), map_line("Dunes")];
+        let mut config = test_config(&["Strand"]);
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand"]);
+        let mut config = test_config(&["Strand"]).with_invert_maps(true);
+        assert_eq!(notified_maps(&mut config, &lines), ["Dunes"]);
+    }
+
+    #[test]
+    fn repeated_map_within_window_notifies_once() {
+        let mut config = test_config(&["Strand"]);
+        let lines = [map_line("Strand"), map_line("Strand")];
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand"]);
+    }
+
+    #[test]
+    fn write_event_notifies_appended_lines() {
+        let mut config = test_config(&["Strand"]);
+        let log = TempLog::new("handle-event.txt");
+        let mut files = HashMap::new();
+        files.insert(
+            log.path.clone(),
+            LogFile::open(log.path.to_str().unwrap()).unwrap(),
+        );
+        let mut session = Session::new(&config).unwrap();
+        let notifier = RecordingNotifier::default();
+        log.append(&[map_line("Dunes"), map_line("Strand")]);
+        let event = Event::new(EventKind::Modify(ModifyKind::Data(
+            notify::event::DataChange::Content,
+        )))
+        .add_path(log.path.clone());
+        handle_event(
+            event,
+            &mut config,
+            &mut files,
+            &mut HashMap::new(),
+            &mut notify::NullWatcher,
+            &mut session,
+            &notifier,
+        )
+        .unwrap();
+        let events = notifier.events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], LogEvent::MapEntered { name, .. } if name == "Strand"));
+    }
+
+    #[test]
+    fn create_event_reopens_rotated_log() {
+        let mut config = test_config(&["Strand"]);
+        let log = TempLog::new("handle-event-rotated.txt");
+        log.append(&[map_line("Dunes")]);
+        let mut files = HashMap::new();
+        files.insert(
+            log.path.clone(),
+            LogFile::open(log.path.to_str().unwrap()).unwrap(),
+        );
+        let mut session = Session::new(&config).unwrap();
+        let notifier = RecordingNotifier::default();
+        fs::remove_file(&log.path).unwrap();
+        log.truncate();
+        log.append(&[map_line("Strand")]);
+        let event = Event::new(EventKind::Create(notify::event::CreateKind::File))
+            .add_path(log.path.clone());
+        handle_event(
+            event,
+            &mut config,
+            &mut files,
+            &mut HashMap::new(),
+            &mut notify::NullWatcher,
+            &mut session,
+            &notifier,
+        )
+        .unwrap();
+        let events = notifier.events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], LogEvent::MapEntered { name, .. } if name == "Strand"));
+    }
+
+    /// Writes `config` with `format` to a file ending in `extension` and
+    /// reads it back.
+    fn round_trip(config: &Config, extension: &str, format: fn(&Config) -> String) -> Config {
+        let file = TempLog::new(&format!("config.{}", extension));
+        fs::write(&file.path, format(config)).unwrap();
+        Config::new_from_file(file.path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn config_round_trips_through_toml_and_json() {
+        let config = test_config(&["Strand", "Dunes"])
+            .with_invert_maps(true)
+            .with_min_price(5.0, HashMap::from([(String::from("divine"), 200.0)]));
+        let toml = round_trip(&config, "toml", |config| config.to_toml().unwrap());
+        assert_eq!(toml.to_toml().unwrap(), config.to_toml().unwrap());
+        let json = round_trip(&config, "json", |config| {
+            serde_json::to_string(config).unwrap()
+        });
+        assert_eq!(json.to_toml().unwrap(), config.to_toml().unwrap());
+    }
+
+    #[test]
+    fn large_log_reads_only_appended_lines() {
+        let mut config = test_config(&["Strand"]);
+        let log = TempLog::new("large.txt");
+        let filler: Vec<String> = (0..200_000)
+            .map(|i| format!("2024/01/01 10:00:00 1 a [INFO] : line {}", i))
+            .collect();
+        log.append(&filler);
+        log.append(&[map_line("Strand")]);
+        let mut watch = Watch::new(&config, &log);
+        log.append(&filler[..1000]);
+        log.append(&[map_line("Strand")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand"]);
+        assert_eq!(watch.session.stats.lines_read, 1001);
+    }
+
+    #[test]
+    fn line_written_in_two_flushes_matches_once() {
+        let mut config = test_config(&["Strand"]);
+        let log = TempLog::new("partial.txt");
+        let mut watch = Watch::new(&config, &log);
+        let line = map_line("Strand");
+        let (start, end) = line.split_at(line.len() / 2);
+        let mut file = OpenOptions::new().append(true).open(&log.path).unwrap();
+        write!(file, "{}", start).unwrap();
+        assert!(watch.read(&mut config, &log).is_empty());
+        writeln!(file, "{}", end).unwrap();
+        assert_eq!(watch.read(&mut config, &log), ["Strand"]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand"]);
+    }
+
+    #[test]
+    fn three_batches_are_read_exactly_once() {
+        let mut config = test_config(&["Strand", "Dunes", "Core"]);
+        let log = TempLog::new("batches.txt");
+        let mut watch = Watch::new(&config, &log);
+        log.append(&[map_line("Strand")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand"]);
+        log.append(&[map_line("Dunes")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand", "Dunes"]);
+        log.append(&[map_line("Core")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand", "Dunes", "Core"]);
+        assert_eq!(watch.session.stats.lines_read, 3);
+    }
+
+    /// Waits up to five seconds for `notifier` to record `count` events.
+    fn wait_for_events(notifier: &RecordingNotifier, count: usize) -> Vec<LogEvent> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while notifier.events().len() < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        notifier.events()
+    }
+
+    #[test]
+    fn run_notifies_lines_appended_to_the_log() {
+        let log = TempLog::new("run.txt");
+        let mut config = Config::new(
+            vec![log.path.to_str().unwrap().to_string()],
+            vec![String::from("Strand"), String::from("Warmup")],
+            MAPS_REGEX,
+            BUY_REGEX,
+        )
+        .unwrap()
+        .with_debounce_ms(10);
+        let notifier = RecordingNotifier::default();
+        let running = AtomicBool::new(true);
+        thread::scope(|scope| {
+            let watching = scope.spawn(|| run_with_notifier(&mut config, &notifier, &running));
+            // the watch only starts once the thread got going
+            while notifier.events().is_empty() {
+                assert!(!watching.is_finished());
+                log.append(&[map_line("Warmup")]);
+                thread::sleep(Duration::from_millis(50));
+            }
+            let warmup = notifier.events().len();
+            log.append(&[
+                map_line("Dunes"),
+                map_line("Strand"),
+                String::from(
+                    "2024/01/01 10:00:01 1 a [INFO] @From Buyer: buy Amulet for 3 chaos in Standard (stash)",
+                ),
+            ]);
+            let events = wait_for_events(&notifier, warmup + 2);
+            running.store(false, Ordering::SeqCst);
+            watching.join().unwrap().unwrap();
+            let events: Vec<&LogEvent> = events
+                .iter()
+                .filter(
+                    |event| !matches!(event, LogEvent::MapEntered { name, .. } if name == "Warmup"),
+                )
+                .collect();
+            assert_eq!(events.len(), 2);
+            assert!(matches!(events[0], LogEvent::MapEntered { name, .. } if name == "Strand"));
+            assert!(matches!(events[1], LogEvent::BuyOffer(offer) if offer.buyer == "Buyer"));
+        });
+    }
+
+    #[test]
+    fn regexes_match_case_insensitively() {
+        let lines = [
+            "2024/01/01 10:00:00 1 a [INFO] : YOU HAVE ENTERED Strand.",
+            "2024/01/01 10:00:01 1 a [INFO] @From Buyer: BUY Amulet FOR 3 chaos IN Standard (stash)",
+        ];
+        let inline = format!(
+            "maps = [\"Strand\"]\nmaps_regex = '(?i){}'\nbuy_regex = '(?i){}'\n",
+            MAPS_REGEX, BUY_REGEX
+        );
+        let flags = format!(
+            "maps = [\"Strand\"]\nmaps_regex = '{}'\nbuy_regex = '{}'\n\
+             maps_regex_case_insensitive = true\nbuy_regex_case_insensitive = true\n",
+            MAPS_REGEX, BUY_REGEX
+        );
+        let plain = format!(
+            "maps = [\"Strand\"]\nmaps_regex = '{}'\nbuy_regex = '{}'\n",
+            MAPS_REGEX, BUY_REGEX
+        );
+        for toml in [inline, flags] {
+            let mut config = Config::new_from_reader(toml.as_bytes()).unwrap();
+            let events = notified(&mut config, &lines);
+            assert_eq!(events.len(), 2, "{}", toml);
+            assert!(matches!(&events[0], LogEvent::MapEntered { name, .. } if name == "Strand"));
+            assert!(matches!(&events[1], LogEvent::BuyOffer(offer) if offer.buyer == "Buyer"));
+        }
+        let mut config = Config::new_from_reader(plain.as_bytes()).unwrap();
+        assert!(notified(&mut config, &lines).is_empty());
+    }
+
+    #[test]
+    fn maps_from_min_tier_are_avoided() {
+        let mut config = Config {
+            min_tier: Some(12),
+            ..Config::new(
+                vec![],
+                vec![],
+                r"You have entered (?P<map>[^(]+) \(T(?P<tier>[^)]+)\)\.",
+                BUY_REGEX,
+            )
+            .unwrap()
+        };
+        let lines = [
+            map_line("Strand (T3)"),
+            map_line("Dunes (T11)"),
+            map_line("Core (T12)"),
+            map_line("Arcade (T16)"),
+            map_line("Beach (Tx)"),
+        ];
+        assert_eq!(notified_maps(&mut config, &lines), ["Core", "Arcade"]);
+        let tiers: Vec<bool> = [None, Some(1), Some(11), Some(12), Some(16)]
+            .iter()
+            .map(|&tier| config.is_avoided_tier(tier))
+            .collect();
+        assert_eq!(tiers, [false, false, false, true, true]);
+    }
+
+    #[test]
+    fn lines_beyond_the_cap_are_skipped() {
+        let mut config = Config {
+            max_lines_per_event: Some(100),
+            ..test_config(&["Strand", "Dunes", "Core"])
+        };
+        let log = TempLog::new("capped.txt");
+        let mut watch = Watch::new(&config, &log);
+        let mut lines: Vec<String> = (0..250).map(|i| format!("line {}", i)).collect();
+        lines[50] = map_line("Strand");
+        lines[200] = map_line("Dunes");
+        log.append(&lines);
+        assert_eq!(watch.read(&mut config, &log), ["Strand"]);
+        assert_eq!(watch.session.stats.lines_read, 100);
+        log.append(&[map_line("Core")]);
+        assert_eq!(watch.read(&mut config, &log), ["Strand", "Core"]);
+    }
+
+    /// Local time `time` on the day of the test lines.
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(&format!("2024/01/01 {}", time), "%Y/%m/%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn lines_older_than_max_age_are_ignored() {
+        let clock = Arc::new(MockClock::new(at("10:10:00")));
+        let mut config = Config {
+            max_event_age_secs: Some(60),
+            ..test_config(&["Strand", "Dunes", "Core"]).with_clock(clock.clone())
+        };
+        let lines = [
+            String::from("2024/01/01 10:00:00 1 a [INFO] : You have entered Strand."),
+            String::from("2024/01/01 10:09:30 1 a [INFO] : You have entered Dunes."),
+            String::from("You have entered Core."),
+        ];
+        assert_eq!(notified_maps(&mut config, &lines), ["Dunes", "Core"]);
+        assert!(config.is_too_old(Some(at("10:08:59"))));
+        assert!(!config.is_too_old(Some(at("10:09:00"))));
+        assert!(!config.is_too_old(None));
+        clock.advance(Duration::from_secs(60));
+        assert!(config.is_too_old(Some(at("10:09:30"))));
+    }
+
+    #[test]
+    fn normalized_names_match_without_suffix() {
+        let lines = [map_line("Strand Map"), map_line("Dunes Map")];
+        let mut config = Config {
+            normalize_map_names: true,
+            avoided_maps: None,
+            ..test_config(&["Strand"])
+        };
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand Map"]);
+        let mut config = Config {
+            normalize_map_names: true,
+            avoided_maps: None,
+            ..test_config(&["Strand Map"])
+        };
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand Map"]);
+        let mut config = Config {
+            normalize_map_names: true,
+            map_name_suffixes: Some(vec![String::from(" Area")]),
+            avoided_maps: None,
+            ..test_config(&["Dunes"])
+        };
+        assert!(notified_maps(&mut config, &lines).is_empty());
+        let mut config = test_config(&["Strand"]);
+        assert!(notified_maps(&mut config, &lines).is_empty());
+    }
+
+    #[test]
+    fn ignored_buyers_are_not_notified() {
+        let mut config = Config {
+            ignored_buyers: vec![String::from("Spammer")],
+            ..test_config(&[])
+        };
+        let events = notified(
+            &mut config,
+            &[
+                "2024/01/01 10:00:00 1 a [INFO] @From SPAMMER: buy Ring for 1 chaos in Standard (stash)",
+                "2024/01/01 10:00:01 1 a [INFO] @From Buyer: buy Amulet for 3 chaos in Standard (stash)",
+            ],
+        );
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], LogEvent::BuyOffer(offer) if offer.buyer == "Buyer"));
+    }
+
+    /// Serializes the tests changing the environment.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Sets the environment variable `name` until dropped.
+    struct EnvVar {
+        name: &'static str,
+        old: Option<std::ffi::OsString>,
+    }
+
+    impl EnvVar {
+        fn set(name: &'static str, value: &str) -> EnvVar {
+            let old = std::env::var_os(name);
+            std::env::set_var(name, value);
+            EnvVar { name, old }
+        }
+    }
+
+    impl Drop for EnvVar {
+        fn drop(&mut self) {
+            match &self.old {
+                Some(old) => std::env::set_var(self.name, old),
+                None => std::env::remove_var(self.name),
+            }
+        }
+    }
+
+    #[test]
+    fn environment_fills_unset_logfile_and_maps() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let _logfile = EnvVar::set(LOGFILE_ENV_VAR, "/logs/Client.txt");
+        let _maps = EnvVar::set(MAPS_ENV_VAR, "Strand, Dunes,,");
+        let mut config = Config::default();
+        config.apply_env_fallbacks();
+        assert_eq!(config.logfiles, ["/logs/Client.txt"]);
+        assert_eq!(config.maps, ["Strand", "Dunes"]);
+        let mut config = Config {
+            logfiles: vec![String::from("Client.txt")],
+            maps: vec![String::from("Core")],
+            ..Default::default()
+        };
+        config.apply_env_fallbacks();
+        assert_eq!(config.logfiles, ["Client.txt"]);
+        assert_eq!(config.maps, ["Core"]);
+    }
+
+    #[test]
+    fn reload_keeps_maps_from_the_environment() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let _maps = EnvVar::set(MAPS_ENV_VAR, "Strand");
+        let file = TempLog::new("reload-env.toml");
+        let toml = format!(
+            "maps_regex = '{}'\nbuy_regex = '{}'\n",
+            MAPS_REGEX, BUY_REGEX
+        );
+        fs::write(&file.path, &toml).unwrap();
+        let mut config = Config::new_from_file(file.path.to_str().unwrap()).unwrap();
+        config.apply_env_fallbacks();
+        fs::write(&file.path, format!("{}invert_maps = true\n", toml)).unwrap();
+        config.reload();
+        assert!(config.invert_maps);
+        assert_eq!(config.maps, ["Strand"]);
+    }
+
+    #[test]
+    fn rapid_notifications_are_limited() {
+        let lines = [map_line("Strand"), map_line("Dunes"), map_line("Core")];
+        let clock = Arc::new(MockClock::new(at("10:00:00")));
+        let mut config = Config {
+            min_notification_interval_ms: Some(500),
+            notification_limit: NotificationLimit::Drop,
+            ..test_config(&["Strand", "Dunes", "Core"]).with_clock(clock.clone())
+        };
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand"]);
+        assert_eq!(clock.local(), at("10:00:00"));
+        config.notification_limit = NotificationLimit::Delay;
+        assert_eq!(
+            notified_maps(&mut config, &lines),
+            ["Strand", "Dunes", "Core"]
+        );
+        assert_eq!(clock.local() - at("10:00:00"), chrono::Duration::seconds(1));
+    }
+
+    #[test]
+    fn map_matching_several_entries_notifies_once() {
+        let mut config = Config {
+            match_mode: MatchMode::Contains,
+            ..test_config(&["re:^Str", "Strand", "Stra"])
+        };
+        assert_eq!(
+            notified_maps(&mut config, &[map_line("Strand")]),
+            ["Strand"]
+        );
+        assert_eq!(
+            config.avoided_map_entry("Strand").unwrap().as_deref(),
+            Some("Strand")
+        );
+        assert_eq!(
+            config.avoided_map_entry("Street").unwrap().as_deref(),
+            Some("re:^Str")
+        );
+        assert_eq!(config.avoided_map_entry("Dunes").unwrap(), None);
+    }
+
+    #[test]
+    fn config_is_read_from_memory() {
+        let toml = format!(
+            "logfile = \"Client.txt\"\nlogfiles = [\"Other.txt\"]\nmaps = [\"Strand\"]\n\
+             invert_maps = true\nmaps_regex = '{}'\nbuy_regex = '{}'\n",
+            MAPS_REGEX, BUY_REGEX
+        );
+        let config = Config::new_from_reader(std::io::Cursor::new(toml)).unwrap();
+        assert_eq!(config.logfiles, ["Client.txt", "Other.txt"]);
+        assert_eq!(config.maps, ["Strand"]);
+        assert!(config.invert_maps);
+        assert_eq!(config.maps_regex, MAPS_REGEX);
+        assert!(matches!(
+            Config::new_from_reader(&b"maps = 1"[..]),
+            Err(AtlasError::TomlError(_))
+        ));
+    }
+
+    #[cfg(feature = "desktop-notifications")]
+    #[test]
+    fn stash_position_is_parsed_when_whispered() {
+        let toml = format!(
+            "maps_regex = '{}'\n{}",
+            MAPS_REGEX,
+            include_str!("../config_example_en.toml")
+                .lines()
+                .find(|line| line.starts_with("buy_regex"))
+                .unwrap()
+        );
+        let mut config = Config::new_from_reader(toml.as_bytes()).unwrap();
+        let events = notified(
+            &mut config,
+            &[
+                "2024/01/01 10:00:00 1 a [INFO] @From Buyer: Hi, I would like to buy your Ring listed for 5 chaos in Standard (stash tab \"Trade\"; position: left 3, top 7)",
+                "2024/01/01 10:00:01 1 a [INFO] @From Other: Hi, I would like to buy your Amulet listed for 2 chaos in Standard",
+            ],
+        );
+        let offers: Vec<&BuyOffer> = events
+            .iter()
+            .filter_map(|event| match event {
+                LogEvent::BuyOffer(offer) => Some(offer),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(offers.len(), 2);
+        assert_eq!(
+            offers[0].stash,
+            Some(StashPosition {
+                tab: String::from("Trade"),
+                left: String::from("3"),
+                top: String::from("7"),
+            })
+        );
+        assert_eq!(offers[1].stash, None);
+        assert_eq!(offers[1].league, "Standard");
+        assert_eq!(
+            buyer_body(&config, offers[0], None),
+            "buyer: <b>Buyer</b>\nobject: <b>Ring</b>\nprice: <b>5 chaos</b>\nleague: <b>Standard</b>\n\
+             location: <b>stash tab \"Trade\"; position: left 3, top 7</b>\n\
+             tab: <b>Trade</b> (left <b>3</b>, top <b>7</b>)"
+        );
+        assert_eq!(
+            buyer_body(&config, offers[1], None),
+            "buyer: <b>Other</b>\nobject: <b>Amulet</b>\nprice: <b>2 chaos</b>\nleague: <b>Standard</b>"
+        );
+    }
+
+    #[test]
+    fn handler_error_stops_watching() {
+        let log = TempLog::new("handler.txt");
+        let mut config = Config::new(
+            vec![log.path.to_str().unwrap().to_string()],
+            vec![],
+            MAPS_REGEX,
+            BUY_REGEX,
+        )
+        .unwrap()
+        .with_debounce_ms(10);
+        let running = AtomicBool::new(true);
+        let mut seen = Vec::new();
+        let result = thread::scope(|scope| {
+            let watching = scope.spawn(|| {
+                run_with_handler(&mut config, &running, |event| {
+                    seen.push(event);
+                    Err(AtlasError::ConfigError(String::from("enough")))
+                })
+            });
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !watching.is_finished() && Instant::now() < deadline {
+                log.append(&[map_line("Strand")]);
+                thread::sleep(Duration::from_millis(50));
+            }
+            running.store(false, Ordering::SeqCst);
+            watching.join().unwrap()
+        });
+        assert!(matches!(result, Err(AtlasError::ConfigError(e)) if e == "enough"));
+        assert_eq!(seen.len(), 1);
+    }
+
+    #[test]
+    fn events_are_routed_to_their_notifiers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || receive_request(listener));
+        let file = TempLog::new("notifications.txt");
+        let toml = format!(
+            "maps = [\"Strand\"]\nmaps_regex = '{}'\nbuy_regex = '{}'\n\
+             map_notifiers = [\"none\"]\nbuyer_notifiers = [\"file\", \"webhook\"]\n\
+             notification_file = '{}'\nwebhook_url = '{}'\n",
+            MAPS_REGEX,
+            BUY_REGEX,
+            file.path.display(),
+            url
+        );
+        let mut config = Config::new_from_reader(toml.as_bytes())
+            .unwrap()
+            .with_clock(Arc::new(MockClock::new(at("10:00:01"))));
+        let notifier = config.notifier().unwrap();
+        let mut session = Session::new(&config).unwrap();
+        for line in [
+            map_line("Strand"),
+            String::from(
+                "2024/01/01 10:00:01 1 a [INFO] @From Buyer: buy Amulet for 3 chaos in Standard (stash)",
+            ),
+        ] {
+            handle_line(&line, &mut config, &mut session, &notifier).unwrap();
+        }
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["type"], "buyer");
+        assert_eq!(
+            fs::read_to_string(&file.path).unwrap(),
+            "buyer: Buyer wants Amulet for 3 chaos in Standard (stash) (0s ago)\n"
+        );
+    }
+
+    #[test]
+    fn notifiers_need_their_settings() {
+        for notifier in ["file", "webhook"] {
+            let toml = format!(
+                "buy_regex = '{}'\nbuyer_notifiers = [\"{}\"]\n",
+                BUY_REGEX, notifier
+            );
+            assert!(matches!(
+                Config::new_from_reader(toml.as_bytes()),
+                Err(AtlasError::ConfigError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn once_reads_a_last_line_without_newline() {
+        let log = TempLog::new("once.txt");
+        fs::write(
+            &log.path,
+            format!("{}\n{}", map_line("Strand"), map_line("Oriath")),
+        )
+        .unwrap();
+        let mut config = Config {
+            once: true,
+            ..Config::new(
+                vec![log.path.to_str().unwrap().to_string()],
+                vec![String::from("Strand"), String::from("Oriath")],
+                MAPS_REGEX,
+                BUY_REGEX,
+            )
+            .unwrap()
+        };
+        let notifier = RecordingNotifier::default();
+        let stats = read_once(&mut config, &notifier).unwrap();
+        assert_eq!(stats.lines_read, 2);
+        assert_eq!(stats.map_alerts, 2);
+    }
+
+    #[test]
+    fn events_carry_their_timestamp() {
+        let mut config = test_config(&["Strand"]);
+        let event = parse_line(&mut config, &map_line("Strand"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.timestamp(), Some(at("10:00:00")));
+        let event = parse_line(
+            &mut config,
+            "2024/01/01 10:00:01 1 a [INFO] @From Buyer: buy Amulet for 3 chaos in Standard (stash)",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(
+            matches!(&event, LogEvent::BuyOffer(offer) if offer.timestamp == Some(at("10:00:01")))
+        );
+        let event = parse_line(&mut config, "You have entered Strand.")
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.timestamp(), None);
+        let lines = [
+            map_line("Strand"),
+            String::from("2024/01/01 10:00:01 1 a [INFO] : You have entered Strand."),
+        ];
+        assert_eq!(notified_maps(&mut config, &lines), ["Strand"]);
+    }
+
+    #[test]
+    fn read_errors_tell_the_line() {
+        let log = TempLog::new("line-number.txt");
+        log.append(&[map_line("Strand"), map_line("Dunes"), map_line("Core")]);
+        let mut file = LogFile::reopen(&log.path).unwrap();
+        file.position = (map_line("Strand").len() + map_line("Dunes").len() + 2) as u64;
+        assert_eq!(file.line_number(), Some(3));
+        file.position = 0;
+        assert_eq!(file.line_number(), Some(1));
+
+        let mut config = test_config(&[]);
+        let directory = std::env::temp_dir();
+        let mut file = LogFile::reopen(&directory).unwrap();
+        let mut session = Session::new(&config).unwrap();
+        let result = read_new_lines(
+            &directory,
+            &mut file,
+            &mut config,
+            &mut session,
+            &RecordingNotifier::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(AtlasError::ReadError { line: Some(1), .. })
+        ));
+    }
+
+    #[test]
+    fn running_twice_installs_no_signal_handler() {
+        let log = TempLog::new("twice.txt");
+        fs::write(&log.path, format!("{}\n", map_line("Strand"))).unwrap();
+        let mut config = Config {
+            once: true,
+            dry_run: true,
+            ..Config::new(
+                vec![log.path.to_str().unwrap().to_string()],
+                vec![String::from("Strand")],
+                MAPS_REGEX,
+                BUY_REGEX,
+            )
+            .unwrap()
+        };
+        let running = AtomicBool::new(true);
+        for _ in 0..2 {
+            let stats = run_with_stats(&mut config, &running).unwrap();
+            assert_eq!(stats.map_alerts, 1);
+        }
+    }
+
+    #[test]
+    fn events_report_read_errors() {
+        let mut config = test_config(&["Strand"]);
+        let log = format!("{}\n", map_line("Strand")).into_bytes();
+        let found = events(&mut config, &log[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        let mut log = log;
+        log.extend_from_slice(b"\xff\xfe\n");
+        let results = events(&mut config, &log[..]).collect::<Vec<_>>();
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), "IoError");
+
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+        let mut results = events(&mut config, BufReader::new(Failing));
+        assert_eq!(results.next().unwrap().unwrap_err().kind(), "IoError");
+    }
+
+    #[test]
+    fn line_lengths_count_only_present_line_breaks() {
+        let lines = log_lines(&b"first\r\nsecond\nlast"[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let lens = lines.iter().map(|line| line.len).collect::<Vec<_>>();
+        assert_eq!(lens, [7, 7, 4]);
+        assert_eq!(lines[0].text, "first");
+        assert_eq!(lines[2].number, 3);
+        assert_eq!(lens.iter().sum::<u64>(), 18);
+    }
+
+    #[test]
+    fn rename_events_follow_rotated_log() {
+        use notify::event::RenameMode;
+        let mut config = test_config(&["Strand", "Dunes"]);
+        let log = TempLog::new("handle-event-renamed.txt");
+        let rotated = TempLog::new("handle-event-renamed.txt.1");
+        log.append(&[map_line("Dunes")]);
+        let mut files = HashMap::new();
+        files.insert(
+            log.path.clone(),
+            LogFile::open(log.path.to_str().unwrap()).unwrap(),
+        );
+        let mut session = Session::new(&config).unwrap();
+        let notifier = RecordingNotifier::default();
+        let mut rename = |mode, path: &Path| {
+            let event =
+                Event::new(EventKind::Modify(ModifyKind::Name(mode))).add_path(path.to_path_buf());
+            handle_event(
+                event,
+                &mut config,
+                &mut files,
+                &mut HashMap::new(),
+                &mut notify::NullWatcher,
+                &mut session,
+                &notifier,
+            )
+            .unwrap();
+        };
+        fs::rename(&log.path, &rotated.path).unwrap();
+        rename(RenameMode::From, &log.path);
+        assert!(notifier.events().is_empty());
+        log.truncate();
+        log.append(&[map_line("Strand")]);
+        rename(RenameMode::To, &log.path);
+        let events = notifier.events();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], LogEvent::MapEntered { name, .. } if name == "Strand"));
+    }
+
+    #[test]
+    fn map_patterns_are_compiled_when_loading() {
+        let toml = format!(
+            "maps = [\"re:^Str\"]\nmaps_regex = '{}'\nbuy_regex = '{}'\n\
+             [[map_group]]\nname = \"beach\"\nmaps = [\"re:Dunes$\"]\n",
+            MAPS_REGEX, BUY_REGEX
+        );
+        let config = Config::new_from_reader(toml.as_bytes()).unwrap();
+        assert!(config.avoided_maps.is_some());
+        assert!(config.map_groups[0].avoided_maps.is_some());
+
+        let invalid = toml.replace("re:Dunes$", "re:(Dunes");
+        let e = Config::new_from_reader(invalid.as_bytes()).unwrap_err();
+        assert_eq!(e.kind(), "RegexError");
+        let mut config = Config::new_from_reader(toml.as_bytes()).unwrap();
+        config.map_groups[0].maps = vec![String::from("re:(Dunes")];
+        assert_eq!(config.validate().unwrap_err().kind(), "RegexError");
+    }
+
+    #[cfg(feature = "desktop-notifications")]
+    #[test]
+    fn notification_backoff_is_capped() {
+        let clock = Arc::new(MockClock::new(at("10:00:00")));
+        let mut config = test_config(&["Strand"]).with_clock(clock.clone());
+        config.notification_retries = Some(5);
+        config.notification_backoff_ms = Some(10_000);
+        let start = clock.now();
+        let attempts = Cell::new(0);
+        let result = show_retrying(&config, || {
+            attempts.set(attempts.get() + 1);
+            Err::<(), _>(notify_rust::error::Error::from("no daemon"))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 6);
+        // 10s, 20s and then 30s for each further retry
+        assert_eq!(clock.now() - start, Duration::from_secs(10 + 20 + 30 * 3));
+    }
+}
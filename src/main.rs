@@ -1,12 +1,32 @@
+use std::env;
 use std::process;
 
+use brickatlas::AtlasError;
+
+/// Prints `e` as JSON to stderr with `--json-errors`, as text otherwise.
+fn report(context: &str, e: AtlasError, json: bool) {
+    if json {
+        eprintln!("{}", e.to_json());
+    } else if let AtlasError::ArgsError(e) = e {
+        // shows the usage like for any other program
+        e.exit();
+    } else {
+        println!("{}: {}", context, e);
+    }
+}
+
 fn main() {
+    let json_errors = env::args().any(|arg| arg == brickatlas::JSON_ERRORS_FLAG);
     let mut config = brickatlas::Config::new_from_args().unwrap_or_else(|e| {
-        println!("error while configuring from command arguments: {}", e);
+        report(
+            "error while configuring from command arguments",
+            e,
+            json_errors,
+        );
         process::exit(1);
     });
     if let Err(e) = brickatlas::run(&mut config) {
-        println!("error while executing: {}", e);
+        report("error while executing", e, json_errors);
         process::exit(1);
     }
 }